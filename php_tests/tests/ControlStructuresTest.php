@@ -0,0 +1,66 @@
+<?php
+
+namespace Test;
+
+use PHPUnit\Framework\TestCase;
+use Test\Utils\SnapshotTestCase;
+use Twig\Environment;
+use Twig\Loader\ArrayLoader;
+
+class ControlStructuresTest extends TestCase
+{
+    use SnapshotTestCase;
+
+    private Environment $twig;
+
+    protected function setUp(): void
+    {
+        $this->twig = new Environment(new ArrayLoader([]));
+    }
+
+    public function testConditionals()
+    {
+        $data = ['user' => ['name' => 'John', 'admin' => false], 'items' => [], 'zero' => '0'];
+        $result = render(__DIR__ . '/fixtures/', 'conditionals.twig', $data, $this->twig);
+        $this->assertSnapshot('conditionals', $result);
+    }
+
+    public function testLoops()
+    {
+        $data = ['users' => ['alice', 'bob', 'carol'], 'empty' => [], 'groups' => ['a' => [1, 2], 'b' => [3]]];
+        $result = render(__DIR__ . '/fixtures/', 'loops.twig', $data, $this->twig);
+        $this->assertSnapshot('loops', $result);
+    }
+
+    public function testLoopIterables()
+    {
+        $data = ['users' => ['carol', 'alice', 'bob'], 'last' => 3, 'iter' => new \ArrayIterator(['x' => 1, 'y' => 2]), 'plain' => new \stdClass()];
+        $result = render(__DIR__ . '/fixtures/', 'loop_iterables.twig', $data, $this->twig);
+        $this->assertSnapshot('loop_iterables', $result);
+    }
+
+    public function testLoopCondition()
+    {
+        $data = ['users' => [
+            ['name' => 'alice', 'active' => true],
+            ['name' => 'bob', 'active' => false],
+            ['name' => 'carol', 'active' => true],
+        ]];
+        $result = render(__DIR__ . '/fixtures/', 'loop_condition.twig', $data, $this->twig);
+        $this->assertSnapshot('loop_condition', $result);
+    }
+
+    public function testApply()
+    {
+        $data = ['name' => 'Ann', 'items' => ['A', 'B'], 'tag' => '<b>'];
+        $result = render(__DIR__ . '/fixtures/', 'apply.twig', $data, $this->twig);
+        $this->assertSnapshot('apply', $result);
+    }
+
+    public function testWhitespaceControl()
+    {
+        $data = ['name' => 'Ann', 'items' => ['A', 'B']];
+        $result = render(__DIR__ . '/fixtures/', 'whitespace_control.twig', $data, $this->twig);
+        $this->assertSnapshot('whitespace_control', $result);
+    }
+}
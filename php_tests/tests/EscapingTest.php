@@ -0,0 +1,49 @@
+<?php
+
+namespace Test;
+
+use PHPUnit\Framework\TestCase;
+use Test\Utils\SnapshotTestCase;
+use Twig\Environment;
+use Twig\Loader\ArrayLoader;
+use Twig\TwigFilter;
+
+class EscapingTest extends TestCase
+{
+    use SnapshotTestCase;
+
+    private const HTML = '<b>"Tom" & \'Jerry\'</b>';
+
+    private Environment $twig;
+
+    protected function setUp(): void
+    {
+        $this->twig = new Environment(new ArrayLoader([]));
+    }
+
+    public function testAutoescape()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'escaping.twig', ['html' => self::HTML, 'flag' => true], $this->twig);
+        $this->assertSnapshot('escaping', $result);
+    }
+
+    public function testStrategyFromExtension()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'escaping.js.twig', ['html' => self::HTML], $this->twig);
+        $this->assertSnapshot('escaping_js', $result);
+
+        $result = render(__DIR__ . '/fixtures/', 'escaping.txt.twig', ['html' => self::HTML], $this->twig);
+        $this->assertSnapshot('escaping_txt', $result);
+    }
+
+    public function testSafeFilters()
+    {
+        $this->twig->addFilter(new TwigFilter(
+            'bold',
+            fn ($s) => "<strong>$s</strong>",
+            ['is_safe' => ['html'], 'pre_escape' => 'html']
+        ));
+        $result = render(__DIR__ . '/fixtures/', 'escapingFilters.twig', ['html' => self::HTML, 'text' => "1 < 2\n3"], $this->twig);
+        $this->assertSnapshot('escapingFilters', $result);
+    }
+}
@@ -6,6 +6,9 @@ use PHPUnit\Framework\TestCase;
 use Test\Utils\SnapshotTestCase;
 use Twig\Environment;
 use Twig\Loader\ArrayLoader;
+use Twig\TwigFilter;
+use Twig\TwigFunction;
+use Twig\TwigTest;
 
 class ExpressionsTest extends TestCase
 {
@@ -30,6 +33,37 @@ class ExpressionsTest extends TestCase
         $this->assertSnapshot('logic', $result);
     }
 
+    public function testOperators()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'operators.twig', [], $this->twig);
+        $this->assertSnapshot('operators', $result);
+    }
+
+    public function testComparison()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'comparison.twig', [], $this->twig);
+        $this->assertSnapshot('comparison', $result);
+    }
+
+    public function testTernary()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'ternary.twig', [], $this->twig);
+        $this->assertSnapshot('ternary', $result);
+    }
+
+    public function testNullCoalescing()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'nullCoalescing.twig', ['user' => ['name' => 'Ann', 'nickname' => null]], $this->twig);
+        $this->assertSnapshot('nullCoalescing', $result);
+    }
+
+    public function testTests()
+    {
+        $this->twig->addTest(new TwigTest('positive', fn ($n) => $n > 0));
+        $result = render(__DIR__ . '/fixtures/', 'tests.twig', ['items' => ['first' => 1]], $this->twig);
+        $this->assertSnapshot('tests', $result);
+    }
+
     public function testStringConcat()
     {
         $result = render(__DIR__ . '/fixtures/', 'strConcat.twig', [], $this->twig);
@@ -42,12 +76,79 @@ class ExpressionsTest extends TestCase
         $this->assertSnapshot('func', $result);
     }
 
+    public function testCoreFunctions()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'functions.twig', [
+            'classes' => ['odd', 'even'],
+            'numbers' => [4, 9, 2],
+            'user' => ['name' => 'Ann', 'email' => 'ann@example.com'],
+            'key' => 'email',
+            'day' => new \DateTimeImmutable('2000-01-01', new \DateTimeZone('UTC')),
+        ], $this->twig);
+        $this->assertSnapshot('functions', $result);
+    }
+
+    public function testFunctionLookupOrder()
+    {
+        $this->twig->addFunction(new TwigFunction('max', fn (...$values) => 'php max'));
+
+        $result = render(__DIR__ . '/fixtures/', 'lookupOrder.twig', [], $this->twig);
+        $this->assertSnapshot('lookupOrder_native', $result);
+
+        $result = render(__DIR__ . '/fixtures/', 'lookupOrder.twig', [], $this->twig, 'php');
+        $this->assertSnapshot('lookupOrder_php', $result);
+    }
+
+    public function testNamedArguments()
+    {
+        $this->twig->addFunction(new TwigFunction('greet', fn (string $name, string $greeting = 'Hello', string $punctuation = '!') => "$greeting $name$punctuation"));
+        $this->twig->addFilter(new TwigFilter('wrap', fn ($value, $openTag = '{', $closeTag = '}') => $openTag . $value . $closeTag));
+
+        $result = render(__DIR__ . '/fixtures/', 'namedArguments.twig', [], $this->twig);
+        $this->assertSnapshot('namedArguments', $result);
+    }
+
     public function testFilter()
     {
         $result = render(__DIR__ . '/fixtures/', 'filter.twig', ['d' => new \DateTimeImmutable('2000-01-01')], $this->twig);
         $this->assertSnapshot('filter', $result);
     }
 
+    public function testCoreFilters()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'coreFilters.twig', [
+            'items' => ['a', 'b', 'c'],
+            'user' => ['name' => 'Ann', 'email' => 'ann@example.com'],
+            'nothing' => null,
+            'numbers' => [3, 1, 10, 2],
+            'words' => ['pear', 'Apple', 'apple', 'banana'],
+            'mixed' => ['list' => [1, 2.5, true, null], 'é/"' => "ü\n"],
+            'text' => "line1\nline2 <b>",
+            'query' => ['q' => 'a b', 'tags' => ['x', 'y'], 'skip' => null, 'on' => true],
+        ], $this->twig);
+        $this->assertSnapshot('coreFilters', $result);
+    }
+
+    public function testSort()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'sort.twig', [
+            'mixed' => [10, '9', null, 'apple', true, 2.5, '10', new \stdClass()],
+            'numbers' => [3, 1, 10, 2],
+            'always' => fn($a, $b) => 1,
+        ], $this->twig);
+        $this->assertSnapshot('sort', $result);
+    }
+
+    public function testFilterChains()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'filterChains.twig', [
+            'items' => ['a', 'b', 'c'],
+            'name' => '  Ann  ',
+            'nothing' => null,
+        ], $this->twig);
+        $this->assertSnapshot('filterChains', $result);
+    }
+
     public function testArrayLiteral()
     {
         $result = render(__DIR__ . '/fixtures/', 'array.twig', [], $this->twig);
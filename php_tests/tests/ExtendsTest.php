@@ -23,4 +23,34 @@ class ExtendsTest extends TestCase
         $result = render(__DIR__ . '/fixtures/', 'extension.twig', [], $this->twig);
         $this->assertSnapshot('extends_basic', $result);
     }
+
+    public function testDynamicExtends()
+    {
+        $data = ['ajax' => true, 'layout' => 'extends_alt.twig'];
+        $result = render(__DIR__ . '/fixtures/', 'dynamic_extends.twig', $data, $this->twig);
+        $this->assertSnapshot('dynamic_extends_ajax', $result);
+
+        $data['ajax'] = false;
+        $result = render(__DIR__ . '/fixtures/', 'dynamic_extends.twig', $data, $this->twig);
+        $this->assertSnapshot('dynamic_extends_page', $result);
+    }
+
+    public function testUse()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'use.twig', ['title' => 'Tape'], $this->twig);
+        $this->assertSnapshot('use', $result);
+    }
+
+    public function testBlockFunction()
+    {
+        $data = ['name' => 'Ann', 'title' => 'Tape'];
+        $result = render(__DIR__ . '/fixtures/', 'block.twig', $data, $this->twig);
+        $this->assertSnapshot('block_function', $result);
+    }
+
+    public function testParent()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'parent.twig', ['owner' => 'Ann'], $this->twig);
+        $this->assertSnapshot('parent', $result);
+    }
 }
\ No newline at end of file
@@ -23,4 +23,18 @@ class IncludesTest extends TestCase
         $result = render(__DIR__ . '/fixtures/', 'include.twig', [], $this->twig);
         $this->assertSnapshot('include', $result);
     }
+
+    public function testIncludeOptions()
+    {
+        $data = ['name' => 'Ann', 'kind' => 'inner'];
+        $result = render(__DIR__ . '/fixtures/', 'includes.twig', $data, $this->twig);
+        $this->assertSnapshot('includes', $result);
+    }
+
+    public function testEmbed()
+    {
+        $data = ['name' => 'Ann', 'text' => 'Global text'];
+        $result = render(__DIR__ . '/fixtures/', 'embed.twig', $data, $this->twig);
+        $this->assertSnapshot('embed', $result);
+    }
 }
\ No newline at end of file
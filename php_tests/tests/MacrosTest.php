@@ -0,0 +1,27 @@
+<?php
+
+namespace Test;
+
+use PHPUnit\Framework\TestCase;
+use Test\Utils\SnapshotTestCase;
+use Twig\Environment;
+use Twig\Loader\ArrayLoader;
+
+class MacrosTest extends TestCase
+{
+    use SnapshotTestCase;
+
+    private Environment $twig;
+
+    protected function setUp(): void
+    {
+        $this->twig = new Environment(new ArrayLoader([]));
+    }
+
+    public function testMacros()
+    {
+        $data = ['name' => '<b>x</b>', 'value' => 'a&b'];
+        $result = render(__DIR__ . '/fixtures/', 'macros.twig', $data, $this->twig);
+        $this->assertSnapshot('macros', $result);
+    }
+}
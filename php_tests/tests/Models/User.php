@@ -0,0 +1,52 @@
+<?php
+
+namespace Test\Models;
+
+class User
+{
+    public string $nickname = 'annie';
+    public ?string $middleName = null;
+    private array $extra = ['mood' => 'happy'];
+
+    public function __construct(private string $name, private bool $active) {}
+
+    public function getName(): string
+    {
+        return $this->name;
+    }
+
+    public function isActive(): bool
+    {
+        return $this->active;
+    }
+
+    public function hasAvatar(): bool
+    {
+        return false;
+    }
+
+    public function greet(string $greeting, string $punctuation = '!'): string
+    {
+        return $greeting . ', ' . $this->name . $punctuation;
+    }
+
+    public function friend(): self
+    {
+        return new self('Bob', false);
+    }
+
+    public function __isset(string $name): bool
+    {
+        return isset($this->extra[$name]);
+    }
+
+    public function __get(string $name): mixed
+    {
+        return $this->extra[$name];
+    }
+
+    public function __call(string $name, array $arguments): string
+    {
+        return 'called ' . $name . '(' . implode(', ', $arguments) . ')';
+    }
+}
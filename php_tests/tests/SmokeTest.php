@@ -23,4 +23,10 @@ class SmokeTest extends TestCase
         $result = render(__DIR__ . '/fixtures/', 'basic.html.twig', ['foo' => ['name' => 'John'], 'coll' => ['a', 'b', 'c']], $this->twig);
         $this->assertSnapshot('basic', $result);
     }
+
+    public function testCommentsAndVerbatim()
+    {
+        $result = render(__DIR__ . '/fixtures/', 'comments.twig', ['name' => 'Ann', 'color' => 'red'], $this->twig);
+        $this->assertSnapshot('comments', $result);
+    }
 }
\ No newline at end of file
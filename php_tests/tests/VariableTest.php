@@ -3,6 +3,7 @@
 namespace Test;
 
 use PHPUnit\Framework\TestCase;
+use Test\Models\User;
 use Test\Utils\SnapshotTestCase;
 use Twig\Environment;
 use Twig\Loader\ArrayLoader;
@@ -23,4 +24,24 @@ class VariableTest extends TestCase
         $result = render(__DIR__ . '/fixtures/', 'variableScopes.twig', [], $this->twig);
         $this->assertSnapshot('variable_scopes', $result);
     }
-}
\ No newline at end of file
+
+    public function testSubscript()
+    {
+        $user = ['name' => 'Ann', 'tags' => ['admin']];
+        $result = render(__DIR__ . '/fixtures/', 'subscript.twig', [
+            'user' => $user,
+            'items' => ['zero', 'one', 'two'],
+            'matrix' => [['a', 'b'], ['c', 'd']],
+            'users' => [$user, $user],
+            'object' => new \ArrayObject(['color' => 'blue']),
+        ], $this->twig);
+        $this->assertSnapshot('subscript', $result);
+    }
+
+    public function testMethodsAndGetters()
+    {
+        $user = new User('Ann', true);
+        $result = render(__DIR__ . '/fixtures/', 'methods.twig', ['user' => $user, 'users' => [$user]], $this->twig);
+        $this->assertSnapshot('methods', $result);
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Result};
+use ext_php_rs::types::Zval;
+
+use super::{call_method, call_php, new_object, value::TaggedValue};
+
+/// A parameter of a function or filter, as far as passing it by name is concerned.
+#[derive(Clone)]
+pub struct Param {
+    /// Alternative names are separated by `|`.
+    pub name: String,
+    /// Passed in place of a skipped argument, `None` when the argument is required.
+    pub default: Option<TaggedValue>,
+}
+
+/// Parameters of a native function or filter, where null stands for the default value.
+pub fn optional(names: &[&str]) -> Vec<Param> {
+    names
+        .iter()
+        .map(|name| Param {
+            name: name.to_string(),
+            default: Some(TaggedValue::Null),
+        })
+        .collect()
+}
+
+/// Moves named arguments to their position, following the `skip` parameters `signature` doesn't
+/// describe (e.g. the filtered value). Skipped arguments in between get their default, like
+/// Twig's `CallExpression::getArguments` does. The signature is only needed when there are
+/// named arguments.
+pub fn bind(
+    params: Vec<TaggedValue>,
+    named: &[(String, TaggedValue)],
+    skip: usize,
+    signature: impl FnOnce() -> Result<Vec<Param>>,
+) -> Result<Vec<TaggedValue>> {
+    if named.is_empty() {
+        return Ok(params);
+    }
+
+    let mut positional = params.into_iter();
+    let mut out: Vec<TaggedValue> = positional.by_ref().take(skip).collect();
+    let mut unused: Vec<&(String, TaggedValue)> = named.iter().collect();
+    let mut defaults = Vec::new();
+    for param in signature()? {
+        let is_param = |name: &str| param.name.split('|').any(|alt| alt == name);
+        let by_name = unused.iter().position(|(name, _)| is_param(name));
+        let val = match (positional.next(), by_name) {
+            (Some(_), Some(_)) => return Err(anyhow!("argument {} is defined twice", param.name)),
+            (Some(val), None) => val,
+            (None, Some(idx)) => unused.remove(idx).1.clone(),
+            (None, None) if unused.is_empty() => break,
+            (None, None) => match param.default {
+                Some(default) => {
+                    defaults.push(default);
+                    continue;
+                }
+                None => return Err(anyhow!("value for argument {} is required", param.name)),
+            },
+        };
+        out.append(&mut defaults);
+        out.push(val);
+    }
+    out.extend(positional);
+
+    match unused.first() {
+        Some((name, _)) => Err(anyhow!("unknown argument {}", name)),
+        None => Ok(out),
+    }
+}
+
+/// The parameters of a PHP callable under the snake cased names templates use for them.
+/// Variadic parameters can't be passed by name, so they end the list.
+pub fn reflect(callable: &Zval) -> Result<Vec<Param>> {
+    let closure = call_php(
+        "Closure::fromCallable",
+        vec![&TaggedValue::Zval(callable.shallow_clone())],
+    )?;
+    let reflection = new_object("ReflectionFunction", vec![&TaggedValue::Zval(closure)])?
+        .ok_or_else(|| anyhow!("class ReflectionFunction not found"))?;
+
+    let mut params = Vec::new();
+    let reflected = call_method(&reflection, "getParameters", vec![])?;
+    for param in reflected.array().into_iter().flat_map(|arr| arr.values()) {
+        if call_method(param, "isVariadic", vec![])?.bool() == Some(true) {
+            break;
+        }
+        let name = call_method(param, "getName", vec![])?;
+        let name = name
+            .str()
+            .ok_or_else(|| anyhow!("parameter without a name"))?;
+        let has_default = call_method(param, "isDefaultValueAvailable", vec![])?.bool();
+        let default = match has_default {
+            Some(true) => Some(call_method(param, "getDefaultValue", vec![])?),
+            _ => None,
+        };
+        params.push(Param {
+            name: snake_case(name),
+            default: default.map(TaggedValue::Zval),
+        });
+    }
+    Ok(params)
+}
+
+/// `thousandSep` becomes `thousand_sep` and `HTMLParser` becomes `html_parser`.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::with_capacity(name.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1);
+        let word_start = c.is_ascii_uppercase()
+            && match prev {
+                Some(prev) if prev.is_ascii_lowercase() || prev.is_ascii_digit() => true,
+                Some(prev) if prev.is_ascii_uppercase() => {
+                    next.is_some_and(char::is_ascii_lowercase)
+                }
+                _ => false,
+            };
+        if word_start {
+            out.push('_');
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out
+}
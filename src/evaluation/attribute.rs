@@ -0,0 +1,99 @@
+use anyhow::Result;
+use ext_php_rs::types::PropertyQuery;
+
+use super::{
+    call_method, call_php,
+    value::{array_get, TaggedValue},
+};
+
+/// Which kinds of lookups an attribute access may resolve to, like Twig's
+/// `Template::ANY_CALL`, `ARRAY_CALL` and `METHOD_CALL`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CallType {
+    /// `foo.bar`
+    Any,
+    /// `foo['bar']`
+    Array,
+    /// `foo.bar(...)`
+    Method,
+}
+
+/// Resolves `val.key` in the same order as Twig's `twig_get_attribute`: array key or
+/// `ArrayAccess` offset, public property (including `__isset`/`__get`), then the methods
+/// `key()`, `getKey()`, `isKey()`, `hasKey()` and finally `__call`. `None` means undefined.
+pub fn get_attribute(
+    val: &TaggedValue,
+    key: &TaggedValue,
+    typ: CallType,
+    args: &[TaggedValue],
+) -> Result<Option<TaggedValue>> {
+    let TaggedValue::Zval(zv) = val.unwrap_safe() else {
+        return Ok(None);
+    };
+
+    if typ != CallType::Method {
+        if let Some(arr) = zv.array() {
+            return Ok(
+                array_get(arr, &key.scalar()).map(|val| TaggedValue::Zval(val.shallow_clone()))
+            );
+        }
+    }
+    let Some(obj) = zv.object() else {
+        return Ok(None);
+    };
+
+    if typ != CallType::Method {
+        let array_access = TaggedValue::from("ArrayAccess");
+        if call_php("is_a", vec![val, &array_access])?.bool() == Some(true)
+            && call_method(zv, "offsetExists", vec![key])?.bool() == Some(true)
+        {
+            return call_method(zv, "offsetGet", vec![key]).map(|val| Some(TaggedValue::Zval(val)));
+        }
+    }
+    if typ == CallType::Array {
+        return Ok(None);
+    }
+
+    let name = key.to_string();
+    if typ == CallType::Any
+        && obj
+            .has_property(&name, PropertyQuery::Exists)
+            .unwrap_or_default()
+    {
+        return Ok(obj.get_property::<TaggedValue>(&name).ok());
+    }
+
+    let params = args.iter().map(|arg| arg as _).collect();
+    match find_method(val, &name)? {
+        Some(method) => call_method(zv, &method, params).map(|val| Some(TaggedValue::Zval(val))),
+        None => Ok(None),
+    }
+}
+
+/// Finds the public method an attribute refers to, case insensitively. Falls back to the
+/// attribute name itself when the class implements `__call`.
+fn find_method(obj: &TaggedValue, name: &str) -> Result<Option<String>> {
+    let methods = call_php("get_class_methods", vec![obj])?;
+    let Some(methods) = methods.array() else {
+        return Ok(None);
+    };
+    let methods: Vec<String> = methods
+        .values()
+        .filter_map(|method| method.str().map(str::to_string))
+        .collect();
+
+    let lc_name = name.to_lowercase();
+    for candidate in [
+        lc_name.clone(),
+        format!("get{}", lc_name),
+        format!("is{}", lc_name),
+        format!("has{}", lc_name),
+    ] {
+        if let Some(method) = methods.iter().find(|m| m.to_lowercase() == candidate) {
+            return Ok(Some(method.clone()));
+        }
+    }
+
+    let magic_call = methods.iter().any(|m| m.eq_ignore_ascii_case("__call"));
+    Ok(magic_call.then(|| name.to_string()))
+}
@@ -1,19 +1,79 @@
 use ext_php_rs::{call_user_func, types::Zval, convert::{IntoZvalDyn, IntoZval}, flags::DataType, ffi::_zval_struct};
 
+use std::{cell::RefCell, collections::HashMap, rc::Rc, str::FromStr};
+
 use anyhow::{anyhow, Result};
 
-use super::{environment::Filter, value::TaggedValue};
+use super::{
+    arguments::{reflect, Param},
+    call_method,
+    environment::Filter,
+    new_object,
+    escape::{escape_value, Strategy},
+    value::{SafeFor, TaggedValue},
+};
 
 pub struct Config {
     twig_env: Zval,
+    lookup_order: LookupOrder,
+    /// PHP filters, functions and tests by name, resolved once since each takes several calls
+    /// to PHP.
+    filters: RefCell<HashMap<String, Filter>>,
+    functions: RefCell<HashMap<String, Filter>>,
+    tests: RefCell<HashMap<String, Filter>>,
+    /// Reflected parameters of PHP functions and filters by name, for binding named arguments.
+    function_signatures: RefCell<HashMap<String, Vec<Param>>>,
+    filter_signatures: RefCell<HashMap<String, Vec<Param>>>,
+}
+
+/// Whether functions are looked up in the native core functions before the ones registered
+/// on the PHP `Twig\Environment`, or the other way around.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LookupOrder {
+    #[default]
+    NativeFirst,
+    PhpFirst,
+}
+
+impl FromStr for LookupOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "native" => Ok(LookupOrder::NativeFirst),
+            "php" => Ok(LookupOrder::PhpFirst),
+            _ => Err(anyhow!("invalid lookup order \"{}\", expected \"native\" or \"php\"", s)),
+        }
+    }
 }
 
 impl Config {
     pub fn new(twig_env: Zval) -> Self {
-        Config { twig_env }
+        Config {
+            twig_env,
+            lookup_order: LookupOrder::default(),
+            filters: RefCell::default(),
+            functions: RefCell::default(),
+            tests: RefCell::default(),
+            function_signatures: RefCell::default(),
+            filter_signatures: RefCell::default(),
+        }
+    }
+
+    pub fn with_lookup_order(mut self, lookup_order: LookupOrder) -> Self {
+        self.lookup_order = lookup_order;
+        self
+    }
+
+    pub fn lookup_order(&self) -> LookupOrder {
+        self.lookup_order
     }
 
-    pub fn get_function(&self, name: &str) -> Result<Zval> {
+    pub fn get_function(&self, name: &str) -> Result<Filter> {
+        cached(&self.functions, name, || self.resolve_function(name))
+    }
+
+    fn resolve_function(&self, name: &str) -> Result<Filter> {
         let funtions = call_user_func!(build_callable(&self.twig_env, "getFunctions"))
             .map_err(|e| anyhow::anyhow!("{}", e))?;
         let func = if let Some(Some(f)) = funtions.array().map(|a| a.get(name)) {
@@ -22,10 +82,20 @@ impl Config {
             return Err(anyhow!("function {} not found", name));
         };
 
-        call_user_func!(build_callable(func, "getCallable")).map_err(|e| anyhow::anyhow!("{}", e))
+        let function = self.wrap_callable(func)?;
+        Ok(match get_safe(func)? {
+            Some(safe_for) => Rc::new(move |params: &Vec<TaggedValue>| -> Result<TaggedValue> {
+                Ok(TaggedValue::Safe(Box::new(function(params)?), safe_for.clone()))
+            }),
+            None => function,
+        })
     }
 
     pub fn get_filter(&self, name: &str) -> Result<Filter> {
+        cached(&self.filters, name, || self.resolve_filter(name))
+    }
+
+    fn resolve_filter(&self, name: &str) -> Result<Filter> {
         let funtions = call_user_func!(build_callable(&self.twig_env, "getFilters"))
             .map_err(|e| anyhow::anyhow!("{}", e))?;
 
@@ -35,28 +105,158 @@ impl Config {
             return Err(anyhow!("function {} not found", name));
         };
 
-        let callable = call_user_func!(build_callable(func, "getCallable")).map_err(|e| anyhow::anyhow!("{}", e))?;
-        let env = ObjAsParamHack{ inner: self.twig_env.shallow_clone() };
+        let filter = self.wrap_callable(func)?;
+        let safe_for = get_safe(func)?;
+        let pre_escape = call_user_func!(build_callable(func, "getPreEscape"))
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .str()
+            .map(str::parse::<Strategy>)
+            .transpose()?;
+        if safe_for.is_none() && pre_escape.is_none() {
+            return Ok(filter);
+        }
+
+        Ok(Rc::new(move |params: &Vec<TaggedValue>| -> Result<TaggedValue> {
+            let mut params = params.clone();
+            if let (Some(strategy), Some(first)) = (pre_escape, params.first_mut()) {
+                if !first.is_safe_for(strategy) {
+                    *first = TaggedValue::Str(escape_value(first, strategy)?);
+                }
+            }
+            let result = filter(&params)?;
+            Ok(match &safe_for {
+                Some(safe_for) => TaggedValue::Safe(Box::new(result), safe_for.clone()),
+                None => result,
+            })
+        }))
+    }
+
+    /// The parameters of a `TwigFunction`'s callable a template can pass by name.
+    pub fn function_params(&self, name: &str) -> Result<Vec<Param>> {
+        cached(&self.function_signatures, name, || self.reflect_function(name))
+    }
 
-        if call_user_func!(build_callable(func, "needsEnvironment")).map_err(|e| anyhow::anyhow!("{}", e))?.bool().unwrap_or_default() {
+    fn reflect_function(&self, name: &str) -> Result<Vec<Param>> {
+        let functions = call_user_func!(build_callable(&self.twig_env, "getFunctions"))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        match functions.array().and_then(|a| a.get(name)) {
+            Some(func) => callable_params(func, 0),
+            None => Err(anyhow!("function {} not found", name)),
+        }
+    }
 
-            Ok(Box::new(move |params: &Vec<TaggedValue>| -> Result<TaggedValue> {
-                let mut z_params: Vec<&dyn IntoZvalDyn> = params.iter().map(|p| p as &dyn IntoZvalDyn).collect();
+    /// The parameters of a `TwigFilter`'s callable a template can pass by name, which leaves
+    /// out the filtered value.
+    pub fn filter_params(&self, name: &str) -> Result<Vec<Param>> {
+        cached(&self.filter_signatures, name, || self.reflect_filter(name))
+    }
+
+    fn reflect_filter(&self, name: &str) -> Result<Vec<Param>> {
+        let filters = call_user_func!(build_callable(&self.twig_env, "getFilters"))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        match filters.array().and_then(|a| a.get(name)) {
+            Some(filter) => callable_params(filter, 1),
+            None => Err(anyhow!("filter {} not found", name)),
+        }
+    }
+
+    /// Calls the PHP callable of a `TwigFilter` or `TwigFunction`, passing the environment
+    /// first when it needs it.
+    fn wrap_callable(&self, twig_callable: &Zval) -> Result<Filter> {
+        let callable = call_user_func!(build_callable(twig_callable, "getCallable"))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let needs_env = call_user_func!(build_callable(twig_callable, "needsEnvironment"))
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .bool()
+            .unwrap_or_default();
+        let env = ObjAsParamHack { inner: self.twig_env.shallow_clone() };
+
+        Ok(Rc::new(move |params: &Vec<TaggedValue>| -> Result<TaggedValue> {
+            let mut z_params: Vec<&dyn IntoZvalDyn> =
+                params.iter().map(|p| p as &dyn IntoZvalDyn).collect();
+            if needs_env {
                 z_params.insert(0, &env);
-                callable.try_call(z_params).map(|zv| TaggedValue::Zval(zv)).map_err(|err| anyhow!("{}", err))
-            }))
+            }
+            callable
+                .try_call(z_params)
+                .map(TaggedValue::Zval)
+                .map_err(|err| anyhow!("{}", err))
+        }))
+    }
+
+    pub fn get_test(&self, name: &str) -> Result<Filter> {
+        cached(&self.tests, name, || self.resolve_test(name))
+    }
+
+    fn resolve_test(&self, name: &str) -> Result<Filter> {
+        let tests = call_user_func!(build_callable(&self.twig_env, "getTests"))
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
 
+        let test = if let Some(Some(t)) = tests.array().map(|a| a.get(name)) {
+            t
         } else {
+            return Err(anyhow!("test {} not found", name));
+        };
 
-            Ok(Box::new(move |params: &Vec<TaggedValue>| -> Result<TaggedValue> {
-                callable.try_call(params.iter().map(|p| p as &dyn IntoZvalDyn).collect()).map(|zv| TaggedValue::Zval(zv)).map_err(|err| anyhow!("{}", err))
-            }))
+        let callable = call_user_func!(build_callable(test, "getCallable")).map_err(|e| anyhow::anyhow!("{}", e))?;
 
-        }
+        Ok(Rc::new(move |params: &Vec<TaggedValue>| -> Result<TaggedValue> {
+            callable.try_call(params.iter().map(|p| p as &dyn IntoZvalDyn).collect()).map(TaggedValue::Zval).map_err(|err| anyhow!("{}", err))
+        }))
+    }
+}
+
+/// The entry for `name` in `cache`, e.g. a filter or its parameters, resolving and caching it
+/// on first use.
+fn cached<T: Clone>(
+    cache: &RefCell<HashMap<String, T>>,
+    name: &str,
+    resolve: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    if let Some(entry) = cache.borrow().get(name) {
+        return Ok(entry.clone());
+    }
+    let entry = resolve()?;
+    cache.borrow_mut().insert(name.to_string(), entry.clone());
+    Ok(entry)
+}
+
+/// Reflects the callable of a `TwigFilter` or `TwigFunction`, skipping what Twig passes
+/// before the template's arguments: the environment, the context and the callable's bound
+/// `arguments`, followed by `skip` more.
+fn callable_params(twig_callable: &Zval, skip: usize) -> Result<Vec<Param>> {
+    let callable = call_method(twig_callable, "getCallable", vec![])?;
+    let needs = |method| -> Result<usize> {
+        Ok(usize::from(call_method(twig_callable, method, vec![])?.bool() == Some(true)))
+    };
+    let bound = call_method(twig_callable, "getArguments", vec![])?
+        .array()
+        .map_or(0, |arr| arr.len());
+    let skip = needs("needsEnvironment")? + needs("needsContext")? + bound + skip;
+    Ok(reflect(&callable)?.into_iter().skip(skip).collect())
+}
+
+/// Strategies a `TwigFilter` output is safe for, from its `is_safe` option or callback.
+/// `getSafe` wants the filter's argument node, a bare `Twig\Node\Node` stands in for it.
+fn get_safe(filter: &Zval) -> Result<Option<SafeFor>> {
+    let Some(node) = new_object("Twig\\Node\\Node", vec![])? else {
+        return Ok(None);
+    };
+
+    let safe = call_method(filter, "getSafe", vec![&TaggedValue::Zval(node)])?;
+    let Some(strategies) = safe.array() else {
+        return Ok(None);
+    };
+    let strategies: Vec<&str> = strategies.values().filter_map(Zval::str).collect();
+    if strategies.contains(&"all") {
+        return Ok(Some(SafeFor::All));
     }
+    Ok(Some(SafeFor::Strategies(
+        strategies.into_iter().filter_map(|s| s.parse().ok()).collect(),
+    )))
 }
 
-fn build_callable(zv: &Zval, fn_name: &str) -> Zval {
+pub(crate) fn build_callable(zv: &Zval, fn_name: &str) -> Zval {
     let mut callable = Zval::new();
     callable.set_array(vec![
         zv.shallow_clone(),
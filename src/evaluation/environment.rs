@@ -1,60 +1,180 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use ext_php_rs::{convert::FromZval, types::Zval};
+use ext_php_rs::{
+    convert::{FromZval, IntoZval},
+    types::{ZendHashTable, Zval},
+};
 
-use crate::loader::{ast::Setter, Loader, Module};
+use crate::loader::{
+    ast::{Block, Macro, Setter},
+    Loader, Module,
+};
 
 use anyhow::{anyhow, Result};
 
-use super::{config::Config, expressions::Evaluate, value::TaggedValue};
+use super::{
+    arguments::Param,
+    attribute::{get_attribute, CallType},
+    config::{Config, LookupOrder},
+    escape::Strategy,
+    expressions::Evaluate,
+    value::{array_get, Scalar, TaggedValue},
+};
 
 pub struct Env {
     globals: Zval,
     stack: Vec<Scope>,
-    loader: Loader,
-    config: Config,
+    /// Imported macros, with one level per scope of `stack`.
+    imports: Vec<HashMap<String, MacroRef>>,
+    loader: Rc<RefCell<Loader>>,
+    config: Rc<Config>,
+    autoescape: Option<Strategy>,
+    /// Every block of the template being rendered, for `block()`.
+    blocks: Rc<HashMap<String, Box<Block>>>,
+    /// The block overridden by the one being rendered, for `parent()`.
+    parent: Option<Rc<Block>>,
 }
 
 type Scope = HashMap<String, TaggedValue>;
 
-pub type Filter = Box<dyn Fn(&Vec<TaggedValue>) -> Result<TaggedValue>>;
+/// What an import binds a name to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroRef {
+    /// All macros of a template, `{% import 'forms.twig' as forms %}`.
+    Template(String),
+    /// A single macro, `{% from 'forms.twig' import input %}`.
+    Macro { template: String, name: String },
+}
+
+pub type Filter = Rc<dyn Fn(&Vec<TaggedValue>) -> Result<TaggedValue>>;
 
 impl Env {
     pub fn new(globals: Zval, loader: Loader, config: Config) -> Self {
         Self {
             globals,
             stack: vec![Scope::default()],
-            loader,
-            config,
+            imports: vec![HashMap::default()],
+            loader: Rc::new(RefCell::new(loader)),
+            config: Rc::new(config),
+            autoescape: Some(Strategy::Html),
+            blocks: Rc::default(),
+            parent: None,
+        }
+    }
+
+    /// A new environment sharing the loader, configuration and blocks but none of the variables
+    /// or imports, for rendering macros.
+    pub fn isolated(&self) -> Self {
+        Self {
+            globals: Zval::new(),
+            stack: vec![Scope::default()],
+            imports: vec![HashMap::default()],
+            loader: Rc::clone(&self.loader),
+            config: Rc::clone(&self.config),
+            autoescape: self.autoescape,
+            blocks: Rc::clone(&self.blocks),
+            parent: None,
         }
     }
 
-    pub fn get_twig_function(&self, name: &str) -> Result<Zval> {
+    pub fn set_blocks(&mut self, blocks: HashMap<String, Box<Block>>) {
+        self.blocks = Rc::new(blocks);
+    }
+
+    pub fn get_block(&self, name: &str) -> Option<&Block> {
+        self.blocks.get(name).map(Box::as_ref)
+    }
+
+    /// Replaces the block `parent()` renders, returning the previous one so it can be restored.
+    pub fn set_parent_block(&mut self, parent: Option<Rc<Block>>) -> Option<Rc<Block>> {
+        std::mem::replace(&mut self.parent, parent)
+    }
+
+    pub fn parent_block(&self) -> Option<&Block> {
+        self.parent.as_deref()
+    }
+
+    /// Strategy applied to printed values, `None` when autoescaping is off.
+    pub fn autoescape(&self) -> Option<Strategy> {
+        self.autoescape
+    }
+
+    /// Replaces the autoescaping strategy, returning the previous one so it can be restored.
+    pub fn set_autoescape(&mut self, strategy: Option<Strategy>) -> Option<Strategy> {
+        std::mem::replace(&mut self.autoescape, strategy)
+    }
+
+    pub fn get_twig_function(&self, name: &str) -> Result<Filter> {
         self.config.get_function(name)
     }
 
+    pub fn lookup_order(&self) -> LookupOrder {
+        self.config.lookup_order()
+    }
+
     pub fn get_twig_filter(&self, name: &str) -> Result<Filter> {
         self.config.get_filter(name)
     }
 
-    pub fn load_file<T: AsRef<str>>(&mut self, file: T) -> Result<Module> {
-        self.loader.load(file)
+    pub fn twig_function_params(&self, name: &str) -> Result<Vec<Param>> {
+        self.config.function_params(name)
+    }
+
+    pub fn twig_filter_params(&self, name: &str) -> Result<Vec<Param>> {
+        self.config.filter_params(name)
+    }
+
+    pub fn get_twig_test(&self, name: &str) -> Result<Filter> {
+        self.config.get_test(name)
+    }
+
+    pub fn load_file<T: AsRef<str>>(&self, file: T) -> Result<Module> {
+        self.loader.borrow_mut().load(file)
+    }
+
+    pub fn load_macro(&self, file: &str, name: &str) -> Result<Option<Rc<Macro>>> {
+        self.loader.borrow_mut().load_macro(file, name)
     }
 
     pub fn enter_new_scope(mut self) -> Self {
         self.stack.push(Scope::default());
+        self.imports.push(HashMap::default());
         self
     }
     pub fn exit_scope(mut self) -> Self {
         self.stack.pop();
+        self.imports.pop();
         self
     }
 
+    /// Binds an imported macro or template in the innermost scope.
+    pub fn import(&mut self, name: &str, target: MacroRef) {
+        self.imports
+            .last_mut()
+            .expect("env should always contain 1 scope")
+            .insert(name.to_string(), target);
+    }
+
+    pub fn get_import(&self, name: &str) -> Option<&MacroRef> {
+        self.imports
+            .iter()
+            .rev()
+            .find_map(|imports| imports.get(name))
+    }
+
     pub fn set(&mut self, name: &str, val: TaggedValue) {
         let scope = self.get_scope(name);
         scope.insert(name.to_string(), val);
     }
 
+    /// Defines `name` in the innermost scope, shadowing any outer variable of the same name.
+    pub fn set_local(&mut self, name: &str, val: TaggedValue) {
+        self.stack
+            .last_mut()
+            .expect("env should always contain 1 scope")
+            .insert(name.to_string(), val);
+    }
+
     pub fn apply_setter(&mut self, setter: &Setter) {
         self.set(
             &setter.target,
@@ -62,39 +182,54 @@ impl Env {
         )
     }
 
-    pub fn get(&self, accessor: &str) -> Result<TaggedValue> {
-        if accessor.is_empty() {
-            return Err(anyhow!("empty varname"));
-        }
+    /// Resolves a dotted access path, each segment with Twig's attribute lookup rules. `None`
+    /// means some segment is undefined, which is distinct from a variable that holds null.
+    pub fn get(&self, accessor: &str) -> Result<Option<TaggedValue>> {
+        let mut segments = accessor.split('.');
+        let Some(mut val) = segments.next().and_then(|name| self.get_var(name)) else {
+            return Ok(None);
+        };
 
-        if let Some(val) = self.get_from_scope(accessor) {
-            return Ok(val);
+        for segment in segments {
+            let key = TaggedValue::from(segment);
+            match get_attribute(&val, &key, CallType::Any, &[])? {
+                Some(attr) => val = attr,
+                None => return Ok(None),
+            }
         }
+        Ok(Some(val))
+    }
 
-        match Self::get_rec(&self.globals, accessor) {
-            Some(zv) => Ok(TaggedValue::Zval(zv.shallow_clone())),
-            None => Err(anyhow!("variable {} was not found", accessor)),
+    /// Snapshot of every variable currently visible, as exposed by `loop.parent`.
+    pub fn context(&self) -> Result<Zval> {
+        let mut ctx = ZendHashTable::new();
+        if let Some(globals) = self.globals.array() {
+            for (idx, key, val) in globals.iter() {
+                match key {
+                    Some(key) => ctx.insert(&key, val.shallow_clone()),
+                    None => ctx.insert_at_index(idx, val.shallow_clone()),
+                }
+                .map_err(|err| anyhow!("{:?}", err))?;
+            }
+        }
+        for scope in self.stack.iter() {
+            for (name, val) in scope.iter() {
+                ctx.insert(name, val.clone())
+                    .map_err(|err| anyhow!("{:?}", err))?;
+            }
         }
+        ctx.into_zval(false).map_err(|err| anyhow!("{:?}", err))
     }
 
-    fn get_from_scope(&self, accessor: &str) -> Option<TaggedValue> {
-        let (key, rest) = if accessor.contains('.') {
-            accessor.split_once('.').unwrap()
-        } else {
-            (accessor, "")
-        };
-
+    fn get_var(&self, name: &str) -> Option<TaggedValue> {
         for scope in self.stack.iter().rev() {
-            if let Some(val) = scope.get(key) {
-                return match val {
-                    TaggedValue::Zval(zv) => {
-                        Self::get_rec(&zv, rest).and_then(TaggedValue::from_zval)
-                    }
-                    _ => Some(val.clone()),
-                };
+            if let Some(val) = scope.get(name) {
+                return Some(val.clone());
             }
         }
-        None
+        let globals = self.globals.array()?;
+        array_get(globals, &Scalar::Str(name.into()))
+            .map(|zv| TaggedValue::Zval(zv.shallow_clone()))
     }
 
     fn get_scope<'env>(&'env mut self, accessor: &'_ str) -> &'env mut Scope {
@@ -111,26 +246,4 @@ impl Env {
             .get_mut(idx)
             .expect("env should always contain 1 scope")
     }
-
-    fn get_rec<'a>(val: &'a Zval, accessor: &'_ str) -> Option<&'a Zval> {
-        if accessor.is_empty() {
-            return Some(val);
-        }
-        let (key, rest) = if accessor.contains('.') {
-            accessor.split_once('.').unwrap()
-        } else {
-            (accessor, "")
-        };
-
-        if val.is_array() {
-            let array = val.array()?;
-            return Self::get_rec(array.get(key)?, rest);
-        }
-
-        if val.is_object() {
-            let obj = val.object()?;
-            return Self::get_rec(obj.get_property(key).ok()?, rest);
-        }
-        None
-    }
 }
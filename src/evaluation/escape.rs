@@ -0,0 +1,185 @@
+use std::{fmt::Write, str::FromStr};
+
+use anyhow::{anyhow, Error, Result};
+
+use super::{
+    call_php,
+    value::{SafeFor, Scalar, TaggedValue},
+};
+
+/// Escaping strategies of Twig's `escape` filter.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Strategy {
+    Html,
+    Js,
+    Css,
+    Url,
+    HtmlAttr,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "html" => Ok(Strategy::Html),
+            "js" => Ok(Strategy::Js),
+            "css" => Ok(Strategy::Css),
+            "url" => Ok(Strategy::Url),
+            "html_attr" => Ok(Strategy::HtmlAttr),
+            _ => Err(anyhow!("invalid escaping strategy \"{}\"", s)),
+        }
+    }
+}
+
+/// Default strategy for a template, following Twig's `FileExtensionEscapingStrategy`:
+/// `.js` and `.css` templates get their own strategy, `.txt` is not escaped and everything
+/// else is treated as HTML. A trailing `.twig` is ignored.
+pub fn default_strategy(template_name: &str) -> Option<Strategy> {
+    let name = template_name.strip_suffix(".twig").unwrap_or(template_name);
+    let extension = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.contains('/'));
+    match extension {
+        Some("js") => Some(Strategy::Js),
+        Some("css") => Some(Strategy::Css),
+        Some("txt") => None,
+        _ => Some(Strategy::Html),
+    }
+}
+
+/// Escapes a value about to be printed. Like Twig, only strings and objects are escaped, other
+/// values are printed as they are.
+pub fn escape_value(val: &TaggedValue, strategy: Strategy) -> Result<String> {
+    match val.scalar() {
+        Scalar::Str(s) => Ok(escape(&s, strategy)),
+        Scalar::Object(_) => {
+            let s = call_php("strval", vec![val.unwrap_safe()])?;
+            Ok(escape(s.str().unwrap_or_default(), strategy))
+        }
+        _ => Ok(val.to_string()),
+    }
+}
+
+/// The `raw` filter: marks its input as safe for every strategy.
+pub fn raw(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = params
+        .first()
+        .map_or(TaggedValue::Null, |val| val.unwrap_safe().clone());
+    Ok(TaggedValue::Safe(Box::new(val), SafeFor::All))
+}
+
+/// The `escape` (or `e`) filter, using the `html` strategy unless another one is given.
+pub fn escape_filter(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = params.first().unwrap_or(&TaggedValue::Null);
+    let strategy = match params.get(1) {
+        Some(strategy) if !strategy.is_null() => strategy.to_string().parse()?,
+        _ => Strategy::Html,
+    };
+    let escaped = match val.scalar() {
+        Scalar::Str(_) | Scalar::Object(_) => TaggedValue::Str(escape_value(val, strategy)?),
+        _ => val.unwrap_safe().clone(),
+    };
+    Ok(TaggedValue::Safe(
+        Box::new(escaped),
+        SafeFor::Strategies(vec![strategy]),
+    ))
+}
+
+pub fn escape(s: &str, strategy: Strategy) -> String {
+    match strategy {
+        Strategy::Html => escape_html(s),
+        Strategy::Js => escape_js(s),
+        Strategy::Css => escape_css(s),
+        Strategy::Url => escape_url(s),
+        Strategy::HtmlAttr => escape_html_attr(s),
+    }
+}
+
+/// `htmlspecialchars($s, ENT_QUOTES | ENT_SUBSTITUTE)`
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#039;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn escape_js(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            c if c.is_ascii_alphanumeric() || matches!(c, ',' | '.' | '_') => out.push(c),
+            '\\' => out.push_str("\\\\"),
+            '/' => out.push_str("\\/"),
+            '\x08' => out.push_str("\\b"),
+            '\x0C' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // characters outside the BMP are written as surrogate pairs
+            c => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    write!(out, "\\u{:04X}", unit).expect("writing to a string can't fail");
+                }
+            }
+        }
+    }
+    out
+}
+
+fn escape_css(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c);
+            continue;
+        }
+        write!(out, "\\{:X} ", c as u32).expect("writing to a string can't fail");
+    }
+    out
+}
+
+/// `rawurlencode($s)`
+fn escape_url(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') => {
+                out.push(b as char)
+            }
+            b => write!(out, "%{:02X}", b).expect("writing to a string can't fail"),
+        }
+    }
+    out
+}
+
+fn escape_html_attr(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            c if c.is_ascii_alphanumeric() || matches!(c, ',' | '.' | '-' | '_') => out.push(c),
+            // control characters other than whitespace are undefined in HTML
+            c if (c.is_ascii_control() && !matches!(c, '\t' | '\n' | '\r')) => {
+                out.push_str("&#xFFFD;")
+            }
+            '"' => out.push_str("&quot;"),
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            c if (c as u32) < 0x100 => {
+                write!(out, "&#x{:02X};", c as u32).expect("writing to a string can't fail")
+            }
+            c => write!(out, "&#x{:04X};", c as u32).expect("writing to a string can't fail"),
+        }
+    }
+    out
+}
@@ -1,17 +1,25 @@
-use crate::loader::{expression::ast::Expression, Operator};
+use crate::loader::{
+    expression::ast::{Expression, NamedArg, Term},
+    Operator,
+};
 
 use super::{
-    environment::Env,
-    value::{self, TaggedValue},
+    arguments,
+    attribute::{get_attribute, CallType},
+    call_php,
+    config::LookupOrder,
+    ensure_traversable,
+    environment::{Env, Filter, MacroRef},
+    filters, functions, includes, macros, twig_tests,
+    value::{Number, Scalar, TaggedValue},
 };
 
 use anyhow::{anyhow, Result};
 use ext_php_rs::{
-    call_user_func,
-    convert::{IntoZval, IntoZvalDyn},
-    types::{ZendHashTable, Zval}
+    convert::IntoZvalDyn,
+    types::ZendHashTable,
 };
-use std::fmt::Write;
+use std::{cmp::Ordering, fmt::Write};
 
 pub trait Evaluate {
     fn eval(&self, env: &Env) -> Result<TaggedValue>;
@@ -24,11 +32,32 @@ trait Apply {
 impl Evaluate for Expression {
     fn eval(&self, env: &Env) -> Result<TaggedValue> {
         match self {
-            Expression::Var(name) => Ok(env.get(name).unwrap_or_default()),
+            Expression::Var(name) => Ok(env.get(name)?.unwrap_or(TaggedValue::Null)),
             Expression::Str(s) => Ok(TaggedValue::Str(s.to_string())),
             Expression::Number(n) => Ok(TaggedValue::Number(*n)),
             Expression::Float(f) => Ok(TaggedValue::Float(*f)),
             Expression::Bool(b) => Ok(TaggedValue::Bool(*b)),
+            Expression::Null => Ok(TaggedValue::Null),
+
+            // the left side may be undefined and the right side is only evaluated when needed
+            Expression::Term(Term {
+                op: Operator::NullCoal,
+                params,
+            }) => match params.as_slice() {
+                [lhs, rhs] => match eval_defined(lhs, env)? {
+                    Some(val) if !val.is_null() => Ok(val),
+                    _ => rhs.eval(env),
+                },
+                _ => Err(anyhow!("?? not implemented for {:?}", params)),
+            },
+
+            Expression::Term(Term {
+                op: Operator::ArrayIndex | Operator::Get,
+                ..
+            })
+            | Expression::MethodCall(_) => {
+                Ok(eval_defined(self, env)?.unwrap_or(TaggedValue::Null))
+            }
 
             Expression::Term(term) => {
                 let params: Result<Vec<TaggedValue>> =
@@ -36,6 +65,52 @@ impl Evaluate for Expression {
                 term.op.apply(params?)
             }
 
+            // only the taken branch is evaluated
+            Expression::Ternary(ternary) => {
+                let condition = ternary.condition.eval(env)?;
+                match (condition.is_truthy(), &ternary.then, &ternary.otherwise) {
+                    (true, Some(then), _) => then.eval(env),
+                    (true, None, _) => Ok(condition),
+                    (false, _, Some(otherwise)) => otherwise.eval(env),
+                    (false, _, None) => Ok(TaggedValue::Str(String::new())),
+                }
+            }
+
+            Expression::Test(test) => {
+                let Some(subject) = test.params.first() else {
+                    return Err(anyhow!("test {} without a subject", test.name));
+                };
+                if test.name == "defined" {
+                    let defined = match subject {
+                        // blocks are looked up rather than rendered
+                        Expression::FuncCall(fc) if fc.name == "block" => {
+                            let params = fc
+                                .params
+                                .iter()
+                                .map(|p| p.eval(env))
+                                .collect::<Result<Vec<TaggedValue>>>()?;
+                            includes::block_defined(params, &eval_named(&fc.named, env)?, env)?
+                        }
+                        subject => eval_defined(subject, env)?.is_some(),
+                    };
+                    return Ok(TaggedValue::Bool(defined));
+                }
+
+                let params: Vec<TaggedValue> = test
+                    .params
+                    .iter()
+                    .map(|p| p.eval(env))
+                    .collect::<Result<Vec<TaggedValue>>>()?;
+
+                match twig_tests::builtin(&test.name) {
+                    Some(builtin) => builtin(&params).map(TaggedValue::Bool),
+                    None => {
+                        let custom = env.get_twig_test(&test.name)?;
+                        custom(&params).map(|val| TaggedValue::Bool(val.is_truthy()))
+                    }
+                }
+            }
+
             Expression::Array(exprs) => {
                 let mut arr = ZendHashTable::new();
                 for expr in exprs {
@@ -53,119 +128,361 @@ impl Evaluate for Expression {
             },
 
             Expression::FuncCall(fc) => {
-                let f = env.get_twig_function(&fc.name)?;
+                if let Some(MacroRef::Macro { template, name }) = env.get_import(&fc.name) {
+                    return macros::call_macro(template, name, &fc.params, &fc.named, env);
+                }
 
                 let params: Vec<TaggedValue> = fc
                     .params
                     .iter()
                     .map(|p| p.eval(env))
                     .collect::<Result<Vec<TaggedValue>>>()?;
-                f.try_call(params.iter().map(|p| p as &dyn IntoZvalDyn).collect())
-                    .map(|zv| TaggedValue::Zval(zv))
-                    .map_err(|err| anyhow!("{}", err))
+                let named = eval_named(&fc.named, env)?;
+                match fc.name.as_str() {
+                    "include" => return includes::include_function(params, &named, env),
+                    "block" => {
+                        let name = params.first().map(TaggedValue::to_string).unwrap_or_default();
+                        return includes::block_function(params, &named, env)?
+                            .ok_or_else(|| anyhow!("block {} is not defined", name));
+                    }
+                    _ => (),
+                }
+
+                let native = |builtin: functions::NativeFunction| {
+                    let signature = || Ok(arguments::optional(functions::arguments(&fc.name)));
+                    builtin(&arguments::bind(params.clone(), &named, 0, signature)?)
+                };
+                let php = |function: Filter| {
+                    let signature = || env.twig_function_params(&fc.name);
+                    function(&arguments::bind(params.clone(), &named, 0, signature)?)
+                };
+                match (functions::builtin(&fc.name), env.lookup_order()) {
+                    (Some(builtin), LookupOrder::NativeFirst) => native(builtin),
+                    (Some(builtin), LookupOrder::PhpFirst) => {
+                        match env.get_twig_function(&fc.name) {
+                            Ok(function) => php(function),
+                            Err(_) => native(builtin),
+                        }
+                    }
+                    (None, _) => php(env.get_twig_function(&fc.name)?),
+                }
             }
 
             Expression::FilterCall(fc) => {
-                let filter = env.get_twig_filter(&fc.name)?;
+                let Some((subject, args)) = fc.params.split_first() else {
+                    return Err(anyhow!("filter {} without a value", fc.name));
+                };
+                apply_filter(&fc.name, subject.eval(env)?, args, &fc.named, env)
+            }
 
-                let params: Vec<TaggedValue> = fc
-                    .params
-                    .iter()
-                    .map(|p| p.eval(env))
-                    .collect::<Result<Vec<TaggedValue>>>()?;
+            Expression::Parent => includes::parent_function(env),
+        }
+    }
+}
 
-                filter(&params)
-            }
+/// Applies the filter `name` to `subject`, followed by the filter's own arguments.
+pub fn apply_filter(
+    name: &str,
+    subject: TaggedValue,
+    args: &[Expression],
+    named: &[NamedArg],
+    env: &Env,
+) -> Result<TaggedValue> {
+    let mut params = vec![subject];
+    for arg in args {
+        params.push(arg.eval(env)?);
+    }
+    let named = eval_named(named, env)?;
 
-            _ => todo!("implement me: {:?}", self),
+    match filters::builtin(name) {
+        Some(builtin) => {
+            let signature = || Ok(arguments::optional(filters::arguments(name)));
+            builtin(&arguments::bind(params, &named, 1, signature)?)
+        }
+        None => {
+            let filter = env.get_twig_filter(name)?;
+            let signature = || env.twig_filter_params(name);
+            filter(&arguments::bind(params, &named, 1, signature)?)
+        }
+    }
+}
+
+fn eval_named(named: &[NamedArg], env: &Env) -> Result<Vec<(String, TaggedValue)>> {
+    named
+        .iter()
+        .map(|arg| Ok((arg.name.clone(), arg.val.eval(env)?)))
+        .collect()
+}
+
+/// The template whose macros were imported under the name `object`, as in `forms.input()`.
+fn imported_template<'a>(object: &Expression, env: &'a Env) -> Option<&'a str> {
+    match object {
+        Expression::Var(alias) => match env.get_import(alias)? {
+            MacroRef::Template(template) => Some(template),
+            MacroRef::Macro { .. } => None,
+        },
+        _ => None,
+    }
+}
+
+/// Evaluates `expr`, yielding `None` instead of null when it refers to something undefined.
+fn eval_defined(expr: &Expression, env: &Env) -> Result<Option<TaggedValue>> {
+    match expr {
+        Expression::Var(name) => env.get(name),
+        Expression::Term(Term {
+            op: op @ (Operator::ArrayIndex | Operator::Get),
+            params,
+        }) => match params.as_slice() {
+            [subject, key] => {
+                let typ = match op {
+                    Operator::ArrayIndex => CallType::Array,
+                    _ => CallType::Any,
+                };
+                match eval_defined(subject, env)? {
+                    Some(subject) => get_attribute(&subject, &key.eval(env)?, typ, &[]),
+                    None => Ok(None),
+                }
+            }
+            _ => Err(anyhow!("attribute access not implemented for {:?}", params)),
+        },
+        Expression::FuncCall(fc) if fc.name == "block" => {
+            let params = fc
+                .params
+                .iter()
+                .map(|p| p.eval(env))
+                .collect::<Result<Vec<TaggedValue>>>()?;
+            includes::block_function(params, &eval_named(&fc.named, env)?, env)
+        }
+        Expression::MethodCall(call) => {
+            if let Some(template) = imported_template(&call.object, env) {
+                let (args, named) = (&call.params, &call.named);
+                return macros::call_macro(template, &call.name, args, named, env).map(Some);
+            }
+            if !call.named.is_empty() {
+                return Err(anyhow!("method {} can't take named arguments", call.name));
+            }
+            match eval_defined(&call.object, env)? {
+                Some(object) => {
+                    let args: Vec<TaggedValue> = call
+                        .params
+                        .iter()
+                        .map(|p| p.eval(env))
+                        .collect::<Result<Vec<TaggedValue>>>()?;
+                    let name = TaggedValue::Str(call.name.clone());
+                    get_attribute(&object, &name, CallType::Method, &args)
+                }
+                None => Ok(None),
+            }
         }
+        _ => expr.eval(env).map(Some),
     }
 }
 
 impl Apply for Operator {
     fn apply(&self, params: Vec<TaggedValue>) -> Result<TaggedValue> {
         match self {
-            Self::Add => add(&params),
-            Self::Mul => mul(&params),
+            Self::Sub | Self::Add if params.len() == 1 => unary(self, &params),
+            Self::Add => arithmetic(&params, i64::checked_add, |lhs, rhs| lhs + rhs),
+            Self::Sub => arithmetic(&params, i64::checked_sub, |lhs, rhs| lhs - rhs),
+            Self::Mul => arithmetic(&params, i64::checked_mul, |lhs, rhs| lhs * rhs),
             Self::Div => div(&params),
             Self::Divi => divi(&params),
+            Self::Modulo => modulo(&params),
+            Self::Exp => exp(&params),
+            Self::Eq => compare(&params, |ord| ord == Some(Ordering::Equal)),
+            Self::Neq => compare(&params, |ord| ord != Some(Ordering::Equal)),
+            Self::Lt => compare(&params, |ord| ord == Some(Ordering::Less)),
+            Self::Gt => compare(&params, |ord| ord == Some(Ordering::Greater)),
+            Self::Lte => compare(&params, |ord| ord.is_some_and(Ordering::is_le)),
+            Self::Gte => compare(&params, |ord| ord.is_some_and(Ordering::is_ge)),
+            Self::Starship => starship(&params),
+            Self::In => contains(&params),
+            Self::Matches => matches(&params),
+            Self::StartsWith => starts_with(&params),
+            Self::EndsWith => ends_with(&params),
+            Self::BAnd => bitwise(&params, |lhs, rhs| lhs & rhs),
+            Self::BOr => bitwise(&params, |lhs, rhs| lhs | rhs),
+            Self::BXor => bitwise(&params, |lhs, rhs| lhs ^ rhs),
             Self::And => and(&params),
             Self::Or => or(&params),
             Self::Not => not(&params),
             Self::StrConcat => str_concat(&params),
+            Self::Range => functions::range(&params),
             _ => Err(anyhow!("missing apply for operator: {:?}", self)),
         }
     }
 }
 
-fn add(params: &[TaggedValue]) -> Result<TaggedValue> {
+fn operands(params: &[TaggedValue]) -> Result<(Number, Number)> {
     match params {
-        [TaggedValue::Number(lhs), TaggedValue::Number(rhs)] => Ok(TaggedValue::Number(lhs + rhs)),
-        [TaggedValue::Float(lhs), TaggedValue::Number(rhs)] => {
-            Ok(TaggedValue::Float(lhs + *rhs as f64))
-        }
-        [TaggedValue::Number(lhs), TaggedValue::Float(rhs)] => {
-            Ok(TaggedValue::Float(*lhs as f64 + rhs))
-        }
-        [TaggedValue::Float(lhs), TaggedValue::Float(rhs)] => Ok(TaggedValue::Float(lhs + rhs)),
-        _ => Err(anyhow!("add not implemented for {:?}", params)),
+        [lhs, rhs] => Ok((
+            lhs.to_number().map_err(|err| anyhow!(err))?,
+            rhs.to_number().map_err(|err| anyhow!(err))?,
+        )),
+        _ => Err(anyhow!("expected two operands, got {:?}", params)),
+    }
+}
+
+fn int_operands(params: &[TaggedValue]) -> Result<(i64, i64)> {
+    let (lhs, rhs) = operands(params)?;
+    Ok((lhs.as_i64(), rhs.as_i64()))
+}
+
+/// Integer arithmetic overflowing into floats, like PHP does.
+fn arithmetic(
+    params: &[TaggedValue],
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<TaggedValue> {
+    let result = match operands(params)? {
+        (Number::Int(lhs), Number::Int(rhs)) => match int_op(lhs, rhs) {
+            Some(n) => Number::Int(n),
+            None => Number::Float(float_op(lhs as f64, rhs as f64)),
+        },
+        (lhs, rhs) => Number::Float(float_op(lhs.as_f64(), rhs.as_f64())),
+    };
+    Ok(result.into())
+}
+
+fn unary(op: &Operator, params: &[TaggedValue]) -> Result<TaggedValue> {
+    let num = params[0].to_number().map_err(|err| anyhow!(err))?;
+    Ok(match (op, num) {
+        (Operator::Sub, Number::Int(n)) => n
+            .checked_neg()
+            .map_or(TaggedValue::Float(-(n as f64)), TaggedValue::Number),
+        (Operator::Sub, Number::Float(fl)) => TaggedValue::Float(-fl),
+        (_, num) => num.into(),
+    })
+}
+
+fn div(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let (lhs, rhs) = operands(params)?;
+    if rhs.as_f64() == 0.0 {
+        return Err(anyhow!("division by zero"));
+    }
+    Ok(TaggedValue::Float(lhs.as_f64() / rhs.as_f64()))
+}
+
+fn divi(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let (lhs, rhs) = operands(params)?;
+    if rhs.as_f64() == 0.0 {
+        return Err(anyhow!("division by zero"));
+    }
+    Ok(TaggedValue::Number((lhs.as_f64() / rhs.as_f64()).floor() as i64))
+}
+
+fn modulo(params: &[TaggedValue]) -> Result<TaggedValue> {
+    match int_operands(params)? {
+        (_, 0) => Err(anyhow!("modulo by zero")),
+        (lhs, rhs) => Ok(TaggedValue::Number(lhs.checked_rem(rhs).unwrap_or(0))),
     }
 }
 
-fn mul(params: &[TaggedValue]) -> Result<TaggedValue> {
+fn exp(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let result = match operands(params)? {
+        (Number::Int(base), Number::Int(exp)) if exp >= 0 => u32::try_from(exp)
+            .ok()
+            .and_then(|exp| base.checked_pow(exp))
+            .map_or(Number::Float((base as f64).powf(exp as f64)), Number::Int),
+        (base, exp) => Number::Float(base.as_f64().powf(exp.as_f64())),
+    };
+    Ok(result.into())
+}
+
+fn compare(params: &[TaggedValue], check: fn(Option<Ordering>) -> bool) -> Result<TaggedValue> {
     match params {
-        [TaggedValue::Number(lhs), TaggedValue::Number(rhs)] => Ok(TaggedValue::Number(lhs * rhs)),
-        [TaggedValue::Float(lhs), TaggedValue::Number(rhs)] => {
-            Ok(TaggedValue::Float(lhs * *rhs as f64))
-        }
-        [TaggedValue::Number(lhs), TaggedValue::Float(rhs)] => {
-            Ok(TaggedValue::Float(*lhs as f64 * rhs))
-        }
-        [TaggedValue::Float(lhs), TaggedValue::Float(rhs)] => Ok(TaggedValue::Float(lhs * rhs)),
-        _ => Err(anyhow!("add not implemented for {:?}", params)),
+        [lhs, rhs] => Ok(TaggedValue::Bool(check(lhs.compare(rhs)))),
+        _ => Err(anyhow!("compare not implemented for {:?}", params)),
     }
 }
 
-fn div(params: &[TaggedValue]) -> Result<TaggedValue> {
+fn starship(params: &[TaggedValue]) -> Result<TaggedValue> {
     match params {
-        [TaggedValue::Number(lhs), TaggedValue::Number(rhs)] => {
-            Ok(TaggedValue::Float(*lhs as f64 / *rhs as f64))
-        }
-        [TaggedValue::Float(lhs), TaggedValue::Number(rhs)] => {
-            Ok(TaggedValue::Float(lhs / *rhs as f64))
-        }
-        [TaggedValue::Number(lhs), TaggedValue::Float(rhs)] => {
-            Ok(TaggedValue::Float(*lhs as f64 / rhs))
+        // uncomparable values (e.g. two different objects) yield 1 in PHP
+        [lhs, rhs] => Ok(TaggedValue::Number(lhs.compare(rhs).map_or(1, |ord| ord as i64))),
+        _ => Err(anyhow!("starship not implemented for {:?}", params)),
+    }
+}
+
+/// Mirrors Twig's `twig_in_filter`: loose membership for arrays and traversables, substring
+/// search for strings.
+fn contains(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let [needle, haystack] = params else {
+        return Err(anyhow!("in not implemented for {:?}", params));
+    };
+    let found = match haystack.scalar() {
+        Scalar::Array(arr) => arr
+            .values()
+            .any(|val| needle.loose_eq(&TaggedValue::Zval(val.shallow_clone()))),
+        Scalar::Object(_) => {
+            let arr = ensure_traversable(haystack.clone())?;
+            let arr = arr.array().ok_or_else(|| anyhow!("object is not traversable"))?;
+            arr.values().any(|val| needle.loose_eq(&TaggedValue::Zval(val.shallow_clone())))
         }
-        [TaggedValue::Float(lhs), TaggedValue::Float(rhs)] => Ok(TaggedValue::Float(lhs / rhs)),
-        _ => Err(anyhow!("add not implemented for {:?}", params)),
+        Scalar::Str(haystack) => match needle.scalar() {
+            Scalar::Str(_) | Scalar::Int(_) | Scalar::Float(_) => {
+                haystack.contains(&needle.to_string())
+            }
+            _ => false,
+        },
+        _ => false,
+    };
+    Ok(TaggedValue::Bool(found))
+}
+
+/// Runs the pattern through PHP's own `preg_match` so the full PCRE syntax is available.
+fn matches(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let [subject, pattern] = params else {
+        return Err(anyhow!("matches not implemented for {:?}", params));
+    };
+    match call_php("preg_match", vec![pattern, subject])?.long() {
+        Some(n) => Ok(TaggedValue::Bool(n == 1)),
+        None => Err(anyhow!("invalid regular expression: {}", pattern)),
     }
 }
 
-fn divi(params: &[TaggedValue]) -> Result<TaggedValue> {
+fn starts_with(params: &[TaggedValue]) -> Result<TaggedValue> {
     match params {
-        [TaggedValue::Number(lhs), TaggedValue::Number(rhs)] => Ok(TaggedValue::Number(lhs / rhs)),
-        _ => Err(anyhow!("add not implemented for {:?}", params)),
+        [lhs, rhs] => Ok(TaggedValue::Bool(match (lhs.scalar(), rhs.scalar()) {
+            (Scalar::Str(lhs), Scalar::Str(rhs)) => lhs.starts_with(rhs.as_ref()),
+            _ => false,
+        })),
+        _ => Err(anyhow!("starts with not implemented for {:?}", params)),
     }
 }
 
+fn ends_with(params: &[TaggedValue]) -> Result<TaggedValue> {
+    match params {
+        [lhs, rhs] => Ok(TaggedValue::Bool(match (lhs.scalar(), rhs.scalar()) {
+            (Scalar::Str(lhs), Scalar::Str(rhs)) => lhs.ends_with(rhs.as_ref()),
+            _ => false,
+        })),
+        _ => Err(anyhow!("ends with not implemented for {:?}", params)),
+    }
+}
+
+fn bitwise(params: &[TaggedValue], op: fn(i64, i64) -> i64) -> Result<TaggedValue> {
+    let (lhs, rhs) = int_operands(params)?;
+    Ok(TaggedValue::Number(op(lhs, rhs)))
+}
+
 fn and(params: &[TaggedValue]) -> Result<TaggedValue> {
     match params {
-        [TaggedValue::Bool(lhs), TaggedValue::Bool(rhs)] => Ok(TaggedValue::Bool(*lhs && *rhs)),
+        [lhs, rhs] => Ok(TaggedValue::Bool(lhs.is_truthy() && rhs.is_truthy())),
         _ => Err(anyhow!("add not implemented for {:?}", params)),
     }
 }
 
 fn or(params: &[TaggedValue]) -> Result<TaggedValue> {
     match params {
-        [TaggedValue::Bool(lhs), TaggedValue::Bool(rhs)] => Ok(TaggedValue::Bool(*lhs || *rhs)),
+        [lhs, rhs] => Ok(TaggedValue::Bool(lhs.is_truthy() || rhs.is_truthy())),
         _ => Err(anyhow!("add not implemented for {:?}", params)),
     }
 }
 
 fn not(params: &[TaggedValue]) -> Result<TaggedValue> {
     match params {
-        [TaggedValue::Bool(b)] => Ok(TaggedValue::Bool(*b == false)),
+        [val] => Ok(TaggedValue::Bool(!val.is_truthy())),
         _ => Err(anyhow!("add not implemented for {:?}", params)),
     }
 }
@@ -182,3 +499,4 @@ fn str_concat(params: &[TaggedValue]) -> Result<TaggedValue> {
     }?;
     Ok(TaggedValue::Str(buf))
 }
+
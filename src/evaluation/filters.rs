@@ -0,0 +1,810 @@
+use std::{
+    cmp::Ordering,
+    fmt::{Display, Write},
+    ops::Range,
+};
+
+use anyhow::{anyhow, Result};
+use ext_php_rs::{
+    convert::IntoZvalDyn,
+    types::{ZendCallable, ZendHashTable},
+};
+use rust_decimal::{prelude::FromPrimitive, Decimal, RoundingStrategy};
+
+use super::{
+    call_php, ensure_traversable,
+    escape::{self, escape_value, Strategy},
+    twig_tests,
+    value::{Number, SafeFor, Scalar, TaggedValue},
+};
+
+/// A built-in filter, called with the filtered value followed by the filter arguments.
+pub type NativeFilter = fn(&[TaggedValue]) -> Result<TaggedValue>;
+
+/// Twig's core filters implemented natively, anything else is looked up on the PHP
+/// environment.
+pub fn builtin(name: &str) -> Option<NativeFilter> {
+    match name {
+        "upper" => Some(upper),
+        "lower" => Some(lower),
+        "title" => Some(title),
+        "capitalize" => Some(capitalize),
+        "trim" => Some(trim),
+        "length" => Some(length),
+        "join" => Some(join),
+        "split" => Some(split),
+        "default" => Some(default),
+        "first" => Some(first),
+        "last" => Some(last),
+        "keys" => Some(keys),
+        "merge" => Some(merge),
+        "slice" => Some(slice),
+        "reverse" => Some(reverse),
+        "sort" => Some(sort),
+        "replace" => Some(replace),
+        "abs" => Some(abs),
+        "round" => Some(round),
+        "number_format" => Some(number_format),
+        "json_encode" => Some(json_encode),
+        "nl2br" => Some(nl2br),
+        "spaceless" => Some(spaceless),
+        "url_encode" => Some(url_encode),
+        "escape" | "e" => Some(escape::escape_filter),
+        "raw" => Some(escape::raw),
+        _ => None,
+    }
+}
+
+/// The names of a core filter's arguments, for passing them as named arguments. They are the
+/// snake cased parameter names of Twig's PHP implementation, alternative names are separated
+/// by `|`.
+pub fn arguments(name: &str) -> &'static [&'static str] {
+    match name {
+        "trim" => &["character_mask", "side"],
+        "join" => &["glue", "and"],
+        "split" => &["delimiter", "limit"],
+        "default" => &["default"],
+        "merge" => &["arr2"],
+        "slice" => &["start", "length", "preserve_keys"],
+        "reverse" => &["preserve_keys"],
+        "sort" => &["arrow"],
+        "replace" => &["from"],
+        "round" => &["precision", "method"],
+        // PHP's own `number_format` calls the first one `decimals`
+        "number_format" => &["decimal|decimals", "decimal_point", "thousand_sep"],
+        "json_encode" => &["options"],
+        "escape" | "e" => &["strategy", "charset", "autoescape"],
+        _ => &[],
+    }
+}
+
+fn upper(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "upper", 0)?;
+    Ok(val.to_string().to_uppercase().into())
+}
+
+fn lower(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "lower", 0)?;
+    Ok(val.to_string().to_lowercase().into())
+}
+
+/// `mb_convert_case($s, MB_CASE_TITLE)`: every word starts upper case, the rest is lower case.
+fn title(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "title", 0)?;
+    let mut out = String::new();
+    let mut word_start = true;
+    for c in val.to_string().chars() {
+        if word_start {
+            out.extend(c.to_uppercase());
+        } else {
+            out.extend(c.to_lowercase());
+        }
+        word_start = !(c.is_alphanumeric() || c == '\'');
+    }
+    Ok(out.into())
+}
+
+fn capitalize(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "capitalize", 0)?.to_string();
+    let mut chars = val.chars();
+    let out = match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    };
+    Ok(out.into())
+}
+
+fn trim(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "trim", 2)?.to_string();
+    let chars = arg(params, 1).map_or(" \t\n\r\0\x0B".to_string(), TaggedValue::to_string);
+    let is_trimmed = |c: char| chars.contains(c);
+    let side = arg(params, 2).map_or("both".to_string(), TaggedValue::to_string);
+    let out = match side.as_str() {
+        "both" => val.trim_matches(is_trimmed),
+        "left" => val.trim_start_matches(is_trimmed),
+        "right" => val.trim_end_matches(is_trimmed),
+        _ => {
+            return Err(anyhow!(
+                "trimming side must be \"left\", \"right\" or \"both\""
+            ))
+        }
+    };
+    Ok(out.into())
+}
+
+/// Mirrors `twig_length_filter`.
+fn length(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "length", 0)?;
+    let len = match val.scalar() {
+        Scalar::Null => 0,
+        Scalar::Array(arr) => arr.len(),
+        Scalar::Object(_) => {
+            if call_php("is_countable", vec![val])?.bool() == Some(true) {
+                return Ok(TaggedValue::Zval(call_php("count", vec![val])?));
+            }
+            if call_php("is_iterable", vec![val])?.bool() == Some(true) {
+                let arr = ensure_traversable(val.clone())?;
+                arr.array().map_or(0, |arr| arr.len())
+            } else {
+                let to_string = TaggedValue::from("__toString");
+                if call_php("method_exists", vec![val, &to_string])?.bool() == Some(true) {
+                    let s = call_php("strval", vec![val])?;
+                    s.str().unwrap_or_default().chars().count()
+                } else {
+                    1
+                }
+            }
+        }
+        _ => val.to_string().chars().count(),
+    };
+    Ok(TaggedValue::Number(len as i64))
+}
+
+fn join(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "join", 2)?;
+    let items: Vec<String> = match entries(val)? {
+        Some(entries) => entries
+            .into_iter()
+            .map(|(_, val)| val.to_string())
+            .collect(),
+        None if val.is_null() => Vec::new(),
+        None => vec![val.to_string()],
+    };
+    let glue = arg(params, 1)
+        .map(TaggedValue::to_string)
+        .unwrap_or_default();
+    let out = match (arg(params, 2), items.split_last()) {
+        (Some(and), Some((last, init))) if !init.is_empty() => {
+            format!("{}{}{}", init.join(&glue), and, last)
+        }
+        _ => items.join(&glue),
+    };
+    Ok(out.into())
+}
+
+/// Mirrors `twig_split_filter`: `explode` for a delimiter, chunks of characters without one.
+fn split(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "split", 2)?.to_string();
+    let delimiter = arg(params, 1)
+        .ok_or_else(|| anyhow!("split filter needs a delimiter"))?
+        .to_string();
+    let limit = arg(params, 2).map(to_int).transpose()?;
+
+    let parts: Vec<String> = if !delimiter.is_empty() {
+        let parts = val.split(delimiter.as_str());
+        match limit {
+            None => parts.map(str::to_string).collect(),
+            Some(limit) if limit > 0 => val
+                .splitn(limit as usize, delimiter.as_str())
+                .map(str::to_string)
+                .collect(),
+            Some(0) => vec![val.clone()],
+            Some(limit) => {
+                let parts: Vec<&str> = parts.collect();
+                let keep = parts.len().saturating_sub(limit.unsigned_abs() as usize);
+                parts[..keep].iter().map(|s| s.to_string()).collect()
+            }
+        }
+    } else if val.is_empty() {
+        vec![val.clone()]
+    } else {
+        let chars: Vec<char> = val.chars().collect();
+        let chunk = limit.filter(|limit| *limit > 1).unwrap_or(1) as usize;
+        chars
+            .chunks(chunk)
+            .map(|chunk| chunk.iter().collect())
+            .collect()
+    };
+    array_value(
+        parts.into_iter().map(|s| (ArrayKey::Index(0), s.into())),
+        false,
+    )
+}
+
+fn default(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "default", 1)?;
+    if twig_tests::is_empty(val)? {
+        Ok(params.get(1).cloned().unwrap_or_default())
+    } else {
+        Ok(val.clone())
+    }
+}
+
+fn first(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "first", 0)?;
+    match entries(val)? {
+        Some(entries) => Ok(entries
+            .into_iter()
+            .next()
+            .map_or(false.into(), |(_, val)| val)),
+        None => Ok(val.to_string().chars().take(1).collect::<String>().into()),
+    }
+}
+
+fn last(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "last", 0)?;
+    match entries(val)? {
+        Some(entries) => Ok(entries
+            .into_iter()
+            .last()
+            .map_or(false.into(), |(_, val)| val)),
+        None => Ok(val
+            .to_string()
+            .chars()
+            .last()
+            .map(String::from)
+            .unwrap_or_default()
+            .into()),
+    }
+}
+
+fn keys(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "keys", 0)?;
+    let keys = entries(val)?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, _)| (ArrayKey::Index(0), key.into()));
+    array_value(keys, false)
+}
+
+/// `array_merge`: string keys of the second array overwrite, numeric keys are appended.
+fn merge(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let [lhs, rhs] = params else {
+        return Err(anyhow!("merge filter takes exactly one argument"));
+    };
+    let not_iterable = |nth| {
+        anyhow!(
+            "the merge filter only works with arrays or \"Traversable\" as {} argument",
+            nth
+        )
+    };
+    let lhs = entries(lhs)?.ok_or_else(|| not_iterable("first"))?;
+    let rhs = entries(rhs)?.ok_or_else(|| not_iterable("second"))?;
+    array_value(lhs.into_iter().chain(rhs), false)
+}
+
+/// `array_slice` for iterables, `mb_substr` for anything else.
+fn slice(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "slice", 3)?;
+    let start = params.get(1).map(to_int).transpose()?.unwrap_or(0);
+    let length = arg(params, 2).map(to_int).transpose()?;
+    let preserve_keys = params.get(3).is_some_and(TaggedValue::is_truthy);
+
+    match entries(val)? {
+        Some(entries) => {
+            let range = slice_range(entries.len(), start, length);
+            let entries = entries.into_iter().skip(range.start).take(range.len());
+            array_value(entries, preserve_keys)
+        }
+        None => {
+            let chars: Vec<char> = val.to_string().chars().collect();
+            let range = slice_range(chars.len(), start, length);
+            Ok(chars[range].iter().collect::<String>().into())
+        }
+    }
+}
+
+fn reverse(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "reverse", 1)?;
+    let preserve_keys = params.get(1).is_some_and(TaggedValue::is_truthy);
+    match entries(val)? {
+        Some(entries) => array_value(entries.into_iter().rev(), preserve_keys),
+        None => Ok(val.to_string().chars().rev().collect::<String>().into()),
+    }
+}
+
+/// `asort`, or `uasort` when given a comparison callable, keeping the keys.
+fn sort(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "sort", 1)?;
+    let entries = entries(val)?
+        .ok_or_else(|| anyhow!("the sort filter only works with arrays or \"Traversable\""))?;
+
+    let sorted = match arg(params, 1) {
+        None => merge_sort(entries, &mut |(_, lhs), (_, rhs)| Ok(lhs.sort_compare(rhs)))?,
+        Some(TaggedValue::Zval(arrow)) => {
+            let arrow = ZendCallable::new(arrow).map_err(|err| anyhow!("{}", err))?;
+            merge_sort(entries, &mut |(_, lhs), (_, rhs)| {
+                let params: Vec<&dyn IntoZvalDyn> = vec![lhs, rhs];
+                let result = arrow.try_call(params).map_err(|err| anyhow!("{}", err))?;
+                let num = TaggedValue::Zval(result).to_number().map_err(|err| anyhow!(err))?;
+                Ok(num.as_f64().partial_cmp(&0.0).unwrap_or(Ordering::Equal))
+            })?
+        }
+        Some(_) => {
+            return Err(anyhow!(
+                "the sort filter expects a callable to compare items"
+            ))
+        }
+    };
+    array_value(sorted, true)
+}
+
+/// A stable merge sort. Unlike `slice::sort_by` it accepts comparisons that are not a total
+/// order, like PHP's loose comparison or a user callback, and stops at the first error.
+fn merge_sort<T>(
+    mut items: Vec<T>,
+    cmp: &mut impl FnMut(&T, &T) -> Result<Ordering>,
+) -> Result<Vec<T>> {
+    if items.len() < 2 {
+        return Ok(items);
+    }
+    let rhs = merge_sort(items.split_off(items.len() / 2), cmp)?;
+    let lhs = merge_sort(items, cmp)?;
+
+    let mut merged = Vec::with_capacity(lhs.len() + rhs.len());
+    let (mut lhs, mut rhs) = (lhs.into_iter().peekable(), rhs.into_iter().peekable());
+    while let (Some(l), Some(r)) = (lhs.peek(), rhs.peek()) {
+        let next = if cmp(l, r)? == Ordering::Greater {
+            rhs.next()
+        } else {
+            lhs.next()
+        };
+        merged.extend(next);
+    }
+    merged.extend(lhs.chain(rhs));
+    Ok(merged)
+}
+
+/// `strtr($s, $from)`: at each position the longest matching key is replaced.
+fn replace(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let [val, from] = params else {
+        return Err(anyhow!("replace filter takes exactly one argument"));
+    };
+    let pairs: Vec<(String, String)> = entries(from)?
+        .ok_or_else(|| anyhow!("the replace filter expects an array or \"Traversable\""))?
+        .into_iter()
+        .map(|(key, val)| (key.to_string(), val.to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect();
+
+    let val = val.to_string();
+    let mut out = String::with_capacity(val.len());
+    let mut rest = val.as_str();
+    while let Some(c) = rest.chars().next() {
+        let found = pairs
+            .iter()
+            .filter(|(key, _)| rest.starts_with(key.as_str()))
+            .max_by_key(|(key, _)| key.len());
+        match found {
+            Some((key, replacement)) => {
+                out.push_str(replacement);
+                rest = &rest[key.len()..];
+            }
+            None => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    Ok(out.into())
+}
+
+fn abs(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "abs", 0)?;
+    match val.to_number().map_err(|err| anyhow!(err))? {
+        Number::Int(n) => Ok(n
+            .checked_abs()
+            .map_or(TaggedValue::Float((n as f64).abs()), TaggedValue::Number)),
+        Number::Float(fl) => Ok(TaggedValue::Float(fl.abs())),
+    }
+}
+
+fn round(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "round", 2)?;
+    let num = val.to_number().map_err(|err| anyhow!(err))?.as_f64();
+    let precision = arg(params, 1).map(to_int).transpose()?.unwrap_or(0) as i32;
+    let method = arg(params, 2).map_or("common".to_string(), TaggedValue::to_string);
+
+    let factor = 10f64.powi(precision);
+    let rounded = match method.as_str() {
+        "common" => (num * factor).round(),
+        "ceil" => (num * factor).ceil(),
+        "floor" => (num * factor).floor(),
+        _ => {
+            return Err(anyhow!(
+                "the round filter only supports the \"common\", \"ceil\", and \"floor\" methods"
+            ))
+        }
+    };
+    Ok(TaggedValue::Float(rounded / factor))
+}
+
+/// PHP's `number_format`, defaulting to no decimals, `.` and `,` like Twig's core extension.
+fn number_format(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "number_format", 3)?;
+    let num = val.to_number().map_or(0.0, |num| num.as_f64());
+    let decimals = arg(params, 1).map(to_int).transpose()?.unwrap_or(0).max(0) as u32;
+    let point = arg(params, 2).map_or(".".to_string(), TaggedValue::to_string);
+    let separator = arg(params, 3).map_or(",".to_string(), TaggedValue::to_string);
+
+    let mut rounded = Decimal::from_f64(num)
+        .ok_or_else(|| anyhow!("{} can't be formatted as a number", num))?
+        .round_dp_with_strategy(decimals, RoundingStrategy::MidpointAwayFromZero);
+    if rounded.is_zero() {
+        rounded.set_sign_positive(true);
+    }
+    let formatted = format!("{:.*}", decimals as usize, rounded.abs());
+    let (int, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+
+    let mut out = String::new();
+    if rounded.is_sign_negative() {
+        out.push('-');
+    }
+    for (n, digit) in int.chars().enumerate() {
+        if n > 0 && (int.len() - n) % 3 == 0 {
+            out.push_str(&separator);
+        }
+        out.push(digit);
+    }
+    if !fraction.is_empty() {
+        out.push_str(&point);
+        out.push_str(fraction);
+    }
+    Ok(out.into())
+}
+
+/// Encodes natively with PHP's default flags; objects and explicit flags go through PHP.
+fn json_encode(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "json_encode", 1)?;
+    let flags = params.get(1).and_then(TaggedValue::as_int).unwrap_or(0);
+    let mut out = String::new();
+    if flags == 0 && write_json(&mut out, &val.scalar()) {
+        return Ok(out.into());
+    }
+    let params: Vec<&dyn IntoZvalDyn> = params.iter().map(|p| p as &dyn IntoZvalDyn).collect();
+    Ok(TaggedValue::Zval(call_php("json_encode", params)?))
+}
+
+/// Writes `val` as JSON, returning `false` for values that need PHP to be encoded.
+fn write_json(out: &mut String, val: &Scalar) -> bool {
+    match val {
+        Scalar::Null => out.push_str("null"),
+        Scalar::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Scalar::Int(n) => out.push_str(&n.to_string()),
+        Scalar::Float(fl) => {
+            let repr = format!("{:?}", fl);
+            if !fl.is_finite() || repr.contains('e') {
+                return false;
+            }
+            out.push_str(&repr);
+        }
+        Scalar::Str(s) => write_json_str(out, s),
+        Scalar::Array(arr) => {
+            let is_list = arr
+                .iter()
+                .enumerate()
+                .all(|(n, (idx, key, _))| key.is_none() && idx == n as u64);
+            out.push(if is_list { '[' } else { '{' });
+            for (n, (idx, key, val)) in arr.iter().enumerate() {
+                if n > 0 {
+                    out.push(',');
+                }
+                if !is_list {
+                    write_json_str(out, &key.unwrap_or_else(|| idx.to_string()));
+                    out.push(':');
+                }
+                if !write_json(out, &Scalar::from(val)) {
+                    return false;
+                }
+            }
+            out.push(if is_list { ']' } else { '}' });
+        }
+        Scalar::Object(_) => return false,
+    }
+    true
+}
+
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '/' => out.push_str("\\/"),
+            '\x08' => out.push_str("\\b"),
+            '\x0C' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_ascii() && !c.is_ascii_control() => out.push(c),
+            c => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    write!(out, "\\u{:04x}", unit).expect("writing to a string can't fail");
+                }
+            }
+        }
+    }
+    out.push('"');
+}
+
+/// Removes whitespace between HTML tags. Like in Twig, the output counts as safe HTML.
+fn spaceless(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "spaceless", 0)?.to_string();
+    let is_space = |c: char| c.is_ascii_whitespace() || c == '\x0B';
+    let mut out = String::with_capacity(val.len());
+    let mut rest = val.as_str();
+    while let Some(end) = rest.find('>') {
+        out.push_str(&rest[..=end]);
+        rest = &rest[end + 1..];
+        let next = rest.trim_start_matches(is_space);
+        if next.starts_with('<') {
+            rest = next;
+        }
+    }
+    out.push_str(rest);
+    let trimmed = out.trim_matches(|c| is_space(c) || c == '\0');
+    Ok(TaggedValue::Safe(
+        Box::new(trimmed.into()),
+        SafeFor::Strategies(vec![Strategy::Html]),
+    ))
+}
+
+/// Escapes its input as HTML unless it is already safe, so the result is safe HTML.
+fn nl2br(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "nl2br", 0)?;
+    let s = if val.is_safe_for(Strategy::Html) {
+        val.to_string()
+    } else {
+        escape_value(val, Strategy::Html)?
+    };
+
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\n' && c != '\r' {
+            out.push(c);
+            continue;
+        }
+        out.push_str("<br />");
+        out.push(c);
+        if let Some(&next) = chars.peek() {
+            if (next == '\n' || next == '\r') && next != c {
+                out.push(next);
+                chars.next();
+            }
+        }
+    }
+    let out = TaggedValue::Str(out);
+    Ok(TaggedValue::Safe(
+        Box::new(out),
+        SafeFor::Strategies(vec![Strategy::Html]),
+    ))
+}
+
+/// `rawurlencode` for strings, `http_build_query` with RFC 3986 encoding for arrays.
+fn url_encode(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let val = subject(params, "url_encode", 0)?;
+    match val.scalar() {
+        Scalar::Array(arr) => {
+            let mut pairs = Vec::new();
+            build_query(&mut pairs, arr, None);
+            Ok(pairs.join("&").into())
+        }
+        _ => Ok(escape::escape(&val.to_string(), Strategy::Url).into()),
+    }
+}
+
+fn build_query(pairs: &mut Vec<String>, arr: &ZendHashTable, prefix: Option<&str>) {
+    for (idx, key, val) in arr.iter() {
+        let key = escape::escape(&key.unwrap_or_else(|| idx.to_string()), Strategy::Url);
+        let key = match prefix {
+            Some(prefix) => format!("{}%5B{}%5D", prefix, key),
+            None => key,
+        };
+        match Scalar::from(val) {
+            Scalar::Null => (),
+            Scalar::Array(inner) => build_query(pairs, inner, Some(&key)),
+            Scalar::Bool(b) => pairs.push(format!("{}={}", key, b as u8)),
+            _ => {
+                let val = TaggedValue::Zval(val.shallow_clone()).to_string();
+                pairs.push(format!("{}={}", key, escape::escape(&val, Strategy::Url)));
+            }
+        }
+    }
+}
+
+enum ArrayKey {
+    Index(u64),
+    Str(String),
+}
+
+impl Display for ArrayKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArrayKey::Index(idx) => write!(f, "{}", idx),
+            ArrayKey::Str(key) => write!(f, "{}", key),
+        }
+    }
+}
+
+impl From<ArrayKey> for TaggedValue {
+    fn from(key: ArrayKey) -> Self {
+        match key {
+            ArrayKey::Index(idx) => TaggedValue::Number(idx as i64),
+            ArrayKey::Str(key) => TaggedValue::Str(key),
+        }
+    }
+}
+
+/// The entries of an array or `Traversable`, `None` for any other value.
+fn entries(val: &TaggedValue) -> Result<Option<Vec<(ArrayKey, TaggedValue)>>> {
+    let collect = |arr: &ZendHashTable| {
+        arr.iter()
+            .map(|(idx, key, val)| {
+                let key = key.map_or(ArrayKey::Index(idx), ArrayKey::Str);
+                (key, TaggedValue::Zval(val.shallow_clone()))
+            })
+            .collect()
+    };
+    match val.scalar() {
+        Scalar::Array(arr) => Ok(Some(collect(arr))),
+        Scalar::Object(_) => Ok(ensure_traversable(val.clone())?.array().map(collect)),
+        _ => Ok(None),
+    }
+}
+
+/// Builds a PHP array. Like PHP's array functions, numeric keys are renumbered unless
+/// `preserve_keys` is set while string keys are always kept.
+fn array_value(
+    entries: impl IntoIterator<Item = (ArrayKey, TaggedValue)>,
+    preserve_keys: bool,
+) -> Result<TaggedValue> {
+    let mut arr = ZendHashTable::new();
+    for (key, val) in entries {
+        match key {
+            ArrayKey::Str(key) => arr.insert(&key, val),
+            ArrayKey::Index(idx) if preserve_keys => arr.insert_at_index(idx, val),
+            ArrayKey::Index(_) => arr.push(val),
+        }
+        .map_err(|err| anyhow!("{:?}", err))?;
+    }
+    Ok(TaggedValue::Zval(
+        arr.as_zval(false).map_err(|err| anyhow!("{:?}", err))?,
+    ))
+}
+
+/// The part of a sequence of `len` items selected by PHP's `array_slice`/`mb_substr`
+/// arguments, where negative values count from the end.
+fn slice_range(len: usize, start: i64, length: Option<i64>) -> Range<usize> {
+    let from_end = |n: i64| len.saturating_sub(n.unsigned_abs() as usize);
+    let start = if start < 0 {
+        from_end(start)
+    } else {
+        (start as usize).min(len)
+    };
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => from_end(length),
+        Some(length) => start.saturating_add(length as usize).min(len),
+    };
+    start..end.max(start)
+}
+
+/// The filtered value, after checking no more than `max_args` arguments were given.
+fn subject<'a>(params: &'a [TaggedValue], name: &str, max_args: usize) -> Result<&'a TaggedValue> {
+    match params {
+        [] => Err(anyhow!("{} filter without a value", name)),
+        [_, args @ ..] if args.len() > max_args => Err(anyhow!(
+            "{} filter takes at most {} arguments",
+            name,
+            max_args
+        )),
+        [val, ..] => Ok(val),
+    }
+}
+
+/// The `n`th parameter unless it is missing or null, in which case the default applies.
+fn arg(params: &[TaggedValue], n: usize) -> Option<&TaggedValue> {
+    params.get(n).filter(|param| !param.is_null())
+}
+
+fn to_int(val: &TaggedValue) -> Result<i64> {
+    val.to_number()
+        .map(|num| num.as_i64())
+        .map_err(|err| anyhow!(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_number_format() {
+        let (float, int) = (TaggedValue::Float, TaggedValue::Number);
+        let cases: [(&[TaggedValue], &str); 8] = [
+            (&[float(1234567.891)], "1,234,568"),
+            (&[float(1234567.891), int(2)], "1,234,567.89"),
+            (
+                &[float(1234567.891), int(2), ",".into(), ".".into()],
+                "1.234.567,89",
+            ),
+            (&[float(0.5)], "1"),
+            (&[float(2.675), int(2)], "2.68"),
+            (&[float(-0.4)], "0"),
+            (&[float(-0.005), int(2)], "-0.01"),
+            (&[float(-1234.5), int(1), "".into()], "-1,2345"),
+        ];
+        for (params, expected) in cases {
+            assert_eq!(number_format(params).unwrap().to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_write_json_str() {
+        let mut out = String::new();
+        write_json_str(&mut out, "a\"b\\c/d\n\t\x01é😀");
+        assert_eq!(out, r#""a\"b\\c\/d\n\t\u0001\u00e9\ud83d\ude00""#);
+    }
+
+    #[test]
+    fn test_spaceless() {
+        let cases = [
+            (
+                "  <div>\n  <p> a  b </p>\n</div>  ",
+                "<div><p> a  b </p></div>",
+            ),
+            ("<b> x </b> y <i>", "<b> x </b> y <i>"),
+            ("\x0B\0<br>\r\n\t<br>\0", "<br><br>"),
+            ("a > b", "a > b"),
+        ];
+        for (input, expected) in cases {
+            let out = spaceless(&[input.into()]).unwrap();
+            assert!(out.is_safe_for(Strategy::Html));
+            assert_eq!(out.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_merge_sort() {
+        let mixed: Vec<TaggedValue> = vec![
+            TaggedValue::Number(10),
+            "9".into(),
+            TaggedValue::Null,
+            "apple".into(),
+            TaggedValue::Bool(true),
+            TaggedValue::Float(2.5),
+            "10".into(),
+        ];
+        let sorted = merge_sort(mixed, &mut |lhs, rhs| Ok(lhs.sort_compare(rhs))).unwrap();
+        let sorted: Vec<String> = sorted.iter().map(TaggedValue::to_string).collect();
+        assert_eq!(sorted, ["", "2.5", "9", "10", "10", "apple", "1"]);
+
+        let always = merge_sort((0..7).collect(), &mut |_, _| Ok(Ordering::Greater)).unwrap();
+        assert_eq!(always, [6, 5, 4, 3, 2, 1, 0]);
+
+        let mut calls = 0;
+        let failed = merge_sort((0..7).collect(), &mut |lhs: &i32, rhs: &i32| {
+            calls += 1;
+            match lhs.cmp(rhs) {
+                Ordering::Equal => Ok(Ordering::Equal),
+                _ => Err(anyhow!("cannot compare {} and {}", lhs, rhs)),
+            }
+        });
+        assert!(failed.is_err());
+        assert_eq!(calls, 1);
+    }
+}
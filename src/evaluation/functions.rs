@@ -0,0 +1,325 @@
+use std::cmp::Ordering;
+
+use anyhow::{anyhow, Result};
+use ext_php_rs::{convert::IntoZvalDyn, types::ZendHashTable};
+
+use super::{
+    attribute::{get_attribute, CallType},
+    call_method, call_php, ensure_traversable, is_a, new_object,
+    value::{numeric, Number, Scalar, TaggedValue},
+};
+
+/// A built-in function, called with the evaluated arguments.
+pub type NativeFunction = fn(&[TaggedValue]) -> Result<TaggedValue>;
+
+/// Twig's core functions implemented natively. Whether they are looked up before or after the
+/// functions of the PHP environment depends on the configured lookup order.
+pub fn builtin(name: &str) -> Option<NativeFunction> {
+    match name {
+        "range" => Some(range),
+        "cycle" => Some(cycle),
+        "constant" => Some(constant),
+        "max" => Some(max),
+        "min" => Some(min),
+        "random" => Some(random),
+        "attribute" => Some(attribute),
+        "date" => Some(date),
+        _ => None,
+    }
+}
+
+/// The names of a core function's arguments, for passing them as named arguments. They are the
+/// snake cased parameter names of Twig's PHP implementation.
+pub fn arguments(name: &str) -> &'static [&'static str] {
+    match name {
+        "range" => &["low", "high", "step"],
+        "cycle" => &["values", "position"],
+        "constant" => &["constant", "object"],
+        "random" => &["values", "max"],
+        "date" => &["date", "timezone"],
+        _ => &[],
+    }
+}
+
+/// Integer and single letter ranges are built natively, everything else (floats, multi byte
+/// strings) is left to PHP's `range`. Also used by the `..` operator.
+pub fn range(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let (start, end, step) = match params {
+        [start, end] => (start, end, None),
+        [start, end, step] => (start, end, Some(step)),
+        _ => return Err(anyhow!("range takes a start, an end and an optional step")),
+    };
+    let step = match step.map(TaggedValue::to_number).transpose() {
+        Ok(None) => 1,
+        Ok(Some(Number::Int(0))) => return Err(anyhow!("range step can't be zero")),
+        Ok(Some(Number::Int(step))) => step.unsigned_abs() as usize,
+        Ok(Some(Number::Float(_))) | Err(_) => return php_range(params),
+    };
+
+    let mut arr = ZendHashTable::new();
+    match (start.scalar(), end.scalar()) {
+        (Scalar::Str(start), Scalar::Str(end))
+            if is_range_letter(&start) && is_range_letter(&end) =>
+        {
+            let (start, end) = (start.as_bytes()[0], end.as_bytes()[0]);
+            for c in ascending_or_descending(start.into(), end.into()).step_by(step) {
+                arr.push((c as u8 as char).to_string())
+                    .map_err(|err| anyhow!("{:?}", err))?;
+            }
+        }
+        _ => match (start.to_number(), end.to_number()) {
+            (Ok(Number::Int(start)), Ok(Number::Int(end))) => {
+                for n in ascending_or_descending(start, end).step_by(step) {
+                    arr.push(n).map_err(|err| anyhow!("{:?}", err))?;
+                }
+            }
+            _ => return php_range(params),
+        },
+    }
+    Ok(TaggedValue::Zval(
+        arr.as_zval(false).map_err(|err| anyhow!("{:?}", err))?,
+    ))
+}
+
+fn php_range(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let params: Vec<&dyn IntoZvalDyn> = params.iter().map(|p| p as &dyn IntoZvalDyn).collect();
+    call_php("range", params).map(TaggedValue::Zval)
+}
+
+fn is_range_letter(s: &str) -> bool {
+    s.len() == 1 && !s.as_bytes()[0].is_ascii_digit()
+}
+
+fn ascending_or_descending(start: i64, end: i64) -> Box<dyn Iterator<Item = i64>> {
+    if start <= end {
+        Box::new(start..=end)
+    } else {
+        Box::new((end..=start).rev())
+    }
+}
+
+/// Mirrors `twig_cycle`: anything but arrays and `ArrayAccess` objects is returned as is.
+fn cycle(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let [values, position] = params else {
+        return Err(anyhow!("cycle takes a list of values and a position"));
+    };
+    let len = match values.scalar() {
+        Scalar::Array(arr) => arr.len() as i64,
+        Scalar::Object(_) if is_a(values, "ArrayAccess")? => {
+            call_php("count", vec![values])?.long().unwrap_or_default()
+        }
+        _ => return Ok(values.clone()),
+    };
+    if len == 0 {
+        return Err(anyhow!("the cycle function does not work on empty arrays"));
+    }
+    let key = TaggedValue::Number(to_int(position)? % len);
+    Ok(get_attribute(values, &key, CallType::Array, &[])?.unwrap_or(TaggedValue::Null))
+}
+
+/// `constant($name)`, or a class constant of the class of `object` when given one.
+fn constant(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let name = match params {
+        [name] => name.clone(),
+        [name, object] => {
+            let class = call_php("get_class", vec![object])?;
+            let class = class
+                .str()
+                .ok_or_else(|| anyhow!("constant expects an object"))?;
+            format!("{}::{}", class, name).into()
+        }
+        _ => return Err(anyhow!("constant takes a name and an optional object")),
+    };
+    call_php("constant", vec![&name]).map(TaggedValue::Zval)
+}
+
+fn max(params: &[TaggedValue]) -> Result<TaggedValue> {
+    extreme(params, "max", |val, max| {
+        val.compare(max).is_some_and(Ordering::is_gt)
+    })
+}
+
+fn min(params: &[TaggedValue]) -> Result<TaggedValue> {
+    extreme(params, "min", |val, min| {
+        val.compare(min).is_some_and(Ordering::is_lt)
+    })
+}
+
+/// PHP's `max`/`min`: the values are either the arguments or the elements of a single array,
+/// compared with PHP 8's loose comparison. The first of several equal values wins.
+fn extreme(
+    params: &[TaggedValue],
+    name: &str,
+    replaces: fn(&TaggedValue, &TaggedValue) -> bool,
+) -> Result<TaggedValue> {
+    let values: Vec<TaggedValue> = match params {
+        [single] => match single.scalar() {
+            Scalar::Array(arr) => arr
+                .values()
+                .map(|val| TaggedValue::Zval(val.shallow_clone()))
+                .collect(),
+            _ => return Err(anyhow!("{} expects an array or at least two values", name)),
+        },
+        _ => params.to_vec(),
+    };
+    let mut values = values.into_iter();
+    let first = values
+        .next()
+        .ok_or_else(|| anyhow!("{} expects at least one value", name))?;
+    Ok(values.fold(
+        first,
+        |best, val| if replaces(&val, &best) { val } else { best },
+    ))
+}
+
+/// Mirrors `twig_random`: a random number when given nothing or numbers, otherwise a random
+/// character of a string or element of an iterable. PHP's `mt_rand` is the source, so
+/// `mt_srand` seeds it.
+fn random(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let values = params.first().unwrap_or(&TaggedValue::Null);
+    let max = params.get(1).filter(|max| !max.is_null());
+    if params.len() > 2 {
+        return Err(anyhow!("random takes at most two arguments"));
+    }
+
+    let items: Vec<TaggedValue> = match values.scalar() {
+        Scalar::Null => {
+            return match max {
+                None => call_php("mt_rand", vec![]).map(TaggedValue::Zval),
+                Some(max) => mt_rand(0, to_int(max)?).map(TaggedValue::Number),
+            }
+        }
+        Scalar::Int(_) | Scalar::Float(_) => {
+            let values = to_int(values)?;
+            let (min, max) = match max {
+                Some(max) => (values, to_int(max)?),
+                None if values < 0 => (values, 0),
+                None => (0, values),
+            };
+            return mt_rand(min, max).map(TaggedValue::Number);
+        }
+        Scalar::Str(s) if s.is_empty() => return Ok(TaggedValue::from("")),
+        Scalar::Str(s) => s.chars().map(|c| c.to_string().into()).collect(),
+        Scalar::Array(arr) => arr
+            .values()
+            .map(|val| TaggedValue::Zval(val.shallow_clone()))
+            .collect(),
+        Scalar::Object(_) if call_php("is_iterable", vec![values])?.bool() == Some(true) => {
+            let arr = ensure_traversable(values.clone())?;
+            arr.array()
+                .map(|arr| {
+                    arr.values()
+                        .map(|val| TaggedValue::Zval(val.shallow_clone()))
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+        _ => return Ok(values.clone()),
+    };
+    if items.is_empty() {
+        return Err(anyhow!(
+            "the random function cannot pick from an empty array"
+        ));
+    }
+    let picked = mt_rand(0, items.len() as i64 - 1)?;
+    Ok(items[picked as usize].clone())
+}
+
+fn mt_rand(min: i64, max: i64) -> Result<i64> {
+    let (min, max) = (TaggedValue::Number(min), TaggedValue::Number(max));
+    call_php("mt_rand", vec![&min, &max])?
+        .long()
+        .ok_or_else(|| anyhow!("mt_rand failed"))
+}
+
+/// Dynamic attribute access, `attribute(object, name, arguments)`.
+fn attribute(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let (object, name, args) = match params {
+        [object, name] => (object, name, Vec::new()),
+        [object, name, args] => {
+            let args = match args.scalar() {
+                Scalar::Array(arr) => arr
+                    .values()
+                    .map(|val| TaggedValue::Zval(val.shallow_clone()))
+                    .collect(),
+                _ => return Err(anyhow!("attribute arguments must be an array")),
+            };
+            (object, name, args)
+        }
+        _ => {
+            return Err(anyhow!(
+                "attribute takes an object, a name and optional arguments"
+            ))
+        }
+    };
+    Ok(get_attribute(object, name, CallType::Any, &args)?.unwrap_or(TaggedValue::Null))
+}
+
+/// Mirrors `twig_date_converter`: a `DateTime` (or the given `DateTimeImmutable`) in the
+/// requested timezone, PHP's default timezone when none is given and the date's own
+/// timezone for `false`.
+fn date(params: &[TaggedValue]) -> Result<TaggedValue> {
+    let date = params.first().unwrap_or(&TaggedValue::Null);
+    let timezone = match params.get(1) {
+        _ if params.len() > 2 => return Err(anyhow!("date takes at most two arguments")),
+        Some(tz) if matches!(tz.scalar(), Scalar::Bool(false)) => None,
+        Some(tz) if is_a(tz, "DateTimeZone")? => Some(tz.clone()),
+        Some(tz) if !tz.is_null() => Some(new_php_object("DateTimeZone", vec![tz])?),
+        _ => Some(default_timezone()?),
+    };
+
+    let date = match date.scalar() {
+        Scalar::Object(_) if is_a(date, "DateTimeImmutable")? => date.clone(),
+        Scalar::Object(_) if is_a(date, "DateTimeInterface")? => {
+            let clone = call_php("DateTime::createFromInterface", vec![date])?;
+            TaggedValue::Zval(clone)
+        }
+        Scalar::Int(_) | Scalar::Float(_) => unix_timestamp(date)?,
+        Scalar::Str(s) if s.chars().all(|c| c.is_ascii_digit()) && !s.is_empty() => {
+            unix_timestamp(date)?
+        }
+        Scalar::Str(s) if s.starts_with('-') && numeric(&s).is_some() => unix_timestamp(date)?,
+        _ => {
+            let timezone = match &timezone {
+                Some(timezone) => timezone.clone(),
+                None => default_timezone()?,
+            };
+            let date = if date.is_null() {
+                TaggedValue::from("now")
+            } else {
+                date.clone()
+            };
+            return new_php_object("DateTime", vec![&date, &timezone]);
+        }
+    };
+    let Some(timezone) = timezone else {
+        return Ok(date);
+    };
+    match date.unwrap_safe() {
+        TaggedValue::Zval(zv) => {
+            call_method(zv, "setTimezone", vec![&timezone]).map(TaggedValue::Zval)
+        }
+        _ => Ok(date.clone()),
+    }
+}
+
+fn unix_timestamp(date: &TaggedValue) -> Result<TaggedValue> {
+    new_php_object("DateTime", vec![&TaggedValue::from(format!("@{}", date))])
+}
+
+fn default_timezone() -> Result<TaggedValue> {
+    let name = TaggedValue::Zval(call_php("date_default_timezone_get", vec![])?);
+    new_php_object("DateTimeZone", vec![&name])
+}
+
+fn new_php_object(class: &str, params: Vec<&dyn IntoZvalDyn>) -> Result<TaggedValue> {
+    new_object(class, params)?
+        .map(TaggedValue::Zval)
+        .ok_or_else(|| anyhow!("class {} not found", class))
+}
+
+fn to_int(val: &TaggedValue) -> Result<i64> {
+    val.to_number()
+        .map(|num| num.as_i64())
+        .map_err(|err| anyhow!(err))
+}
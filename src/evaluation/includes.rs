@@ -0,0 +1,207 @@
+use std::{collections::HashMap, fmt::Write, io};
+
+use anyhow::{anyhow, Result};
+use ext_php_rs::{convert::FromZval, types::Zval};
+
+use crate::loader::{
+    ast::{get_blocks, Block, Contents, Include},
+    Module,
+};
+
+use super::{
+    arguments, ensure_traversable,
+    environment::Env,
+    expressions::Evaluate,
+    resolve,
+    value::{SafeFor, Scalar, TaggedValue},
+    Renderable,
+};
+
+/// Renders the template of an `include` tag in place.
+pub fn include<T: Write>(include: &Include, out: &mut T, env: Env) -> Result<Env> {
+    render_include(include, HashMap::default(), out, &env)?;
+    Ok(env)
+}
+
+/// Renders the template of an `embed` tag with its blocks overridden by those in `blocks`. The
+/// overrides apply to this embed only, since the loader hands out a copy of the template.
+pub fn embed<T: Write>(include: &Include, blocks: &Contents, out: &mut T, env: Env) -> Result<Env> {
+    let overrides = get_blocks(blocks.clone(), HashMap::default());
+    render_include(include, overrides, out, &env)?;
+    Ok(env)
+}
+
+/// `include(template, variables, with_context, ignore_missing)`, the function form of the
+/// `include` tag, returning the rendered template.
+pub fn include_function(
+    params: Vec<TaggedValue>,
+    named: &[(String, TaggedValue)],
+    env: &Env,
+) -> Result<TaggedValue> {
+    let signature = || {
+        Ok(arguments::optional(&[
+            "template",
+            "variables",
+            "with_context",
+            "ignore_missing",
+            "sandboxed",
+        ]))
+    };
+    let mut params = arguments::bind(params, named, 0, signature)?.into_iter();
+    let mut next = || params.next().unwrap_or(TaggedValue::Null);
+    let (template, vars, with_context, ignore_missing) = (next(), next(), next(), next());
+
+    let mut out = String::new();
+    if let Some(module) = load(&template, ignore_missing.is_truthy(), env)? {
+        let vars = (!vars.is_null()).then_some(vars);
+        let only = !with_context.is_null() && !with_context.is_truthy();
+        render(module, HashMap::default(), vars, only, &mut out, env)?;
+    }
+    Ok(TaggedValue::Safe(Box::new(out.into()), SafeFor::All))
+}
+
+/// `block(name, template)`, renders a block of the current template, or of `template`, with
+/// the current variables. `None` when there is no such block.
+pub fn block_function(
+    params: Vec<TaggedValue>,
+    named: &[(String, TaggedValue)],
+    env: &Env,
+) -> Result<Option<TaggedValue>> {
+    let Some((block, inner)) = find_block(params, named, env)? else {
+        return Ok(None);
+    };
+    let mut out = String::new();
+    block.render(&mut out, inner)?;
+    Ok(Some(TaggedValue::Safe(Box::new(out.into()), SafeFor::All)))
+}
+
+/// `block(name, template) is defined`, looks the block up without rendering it.
+pub fn block_defined(
+    params: Vec<TaggedValue>,
+    named: &[(String, TaggedValue)],
+    env: &Env,
+) -> Result<bool> {
+    Ok(find_block(params, named, env)?.is_some())
+}
+
+/// The block `block(name, template)` refers to, with the environment to render it in.
+fn find_block(
+    params: Vec<TaggedValue>,
+    named: &[(String, TaggedValue)],
+    env: &Env,
+) -> Result<Option<(Block, Env)>> {
+    let signature = || Ok(arguments::optional(&["name", "template"]));
+    let mut params = arguments::bind(params, named, 0, signature)?.into_iter();
+    let mut next = || params.next().unwrap_or(TaggedValue::Null);
+    let (name, template) = (next().to_string(), next());
+
+    // the variables are bound first, since the template may extend a variable
+    let mut inner = env.isolated();
+    bind(&mut inner, &env.context()?);
+    if !template.is_null() {
+        let Some(module) = load(&template, false, env)? else {
+            return Ok(None);
+        };
+        inner.set_blocks(resolve(module, HashMap::default(), &inner)?.block_table());
+    }
+    let block = inner.get_block(&name).cloned();
+    Ok(block.map(|block| (block, inner)))
+}
+
+/// `parent()`, renders the block overridden by the one being rendered.
+pub fn parent_function(env: &Env) -> Result<TaggedValue> {
+    let parent = env
+        .parent_block()
+        .ok_or_else(|| anyhow!("parent() can only be used in a block overriding another block"))?
+        .clone();
+
+    let mut inner = env.isolated();
+    bind(&mut inner, &env.context()?);
+    let mut out = String::new();
+    parent.render(&mut out, inner)?;
+    Ok(TaggedValue::Safe(Box::new(out.into()), SafeFor::All))
+}
+
+fn render_include<T: Write>(
+    include: &Include,
+    blocks: HashMap<String, Box<Block>>,
+    out: &mut T,
+    env: &Env,
+) -> Result<()> {
+    let template = include.template.eval(env)?;
+    let Some(module) = load(&template, include.ignore_missing, env)? else {
+        return Ok(());
+    };
+    let vars = match &include.vars {
+        Some(vars) => Some(vars.eval(env)?),
+        None => None,
+    };
+    render(module, blocks, vars, include.only, out, env)
+}
+
+/// Loads `template`, or the first template of a list that exists. `None` when none does and
+/// missing templates are ignored.
+pub fn load(template: &TaggedValue, ignore_missing: bool, env: &Env) -> Result<Option<Module>> {
+    let candidates = match template.scalar() {
+        Scalar::Array(arr) => arr
+            .values()
+            .map(|name| {
+                TaggedValue::from_zval(name)
+                    .expect("php vm broke")
+                    .to_string()
+            })
+            .collect(),
+        _ => vec![template.to_string()],
+    };
+    for name in candidates.iter() {
+        match env.load_file(name) {
+            Ok(module) => return Ok(Some(module)),
+            Err(err) if is_not_found(&err) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    match (ignore_missing, candidates.as_slice()) {
+        (true, _) => Ok(None),
+        (false, [name]) => Err(anyhow!("unable to find template {}", name)),
+        (false, _) => Err(anyhow!(
+            "unable to find one of the following templates: {}",
+            candidates.join(", ")
+        )),
+    }
+}
+
+/// Renders an included template with its blocks overridden by `blocks`, in an environment of
+/// its own so variables it sets don't leak out. That environment starts with a copy of the
+/// current variables, unless `only` is set, and `vars` on top.
+fn render<T: Write>(
+    module: Module,
+    blocks: HashMap<String, Box<Block>>,
+    vars: Option<TaggedValue>,
+    only: bool,
+    out: &mut T,
+    env: &Env,
+) -> Result<()> {
+    let mut inner = env.isolated();
+    if !only {
+        bind(&mut inner, &env.context()?);
+    }
+    if let Some(vars) = vars {
+        bind(&mut inner, &ensure_traversable(vars)?);
+    }
+    let template = resolve(module, blocks, &inner)?;
+    template.render(out, inner)?;
+    Ok(())
+}
+
+fn bind(env: &mut Env, vars: &Zval) {
+    for (idx, key, val) in vars.array().iter().flat_map(|arr| arr.iter()) {
+        let key = key.unwrap_or_else(|| idx.to_string());
+        env.set_local(&key, TaggedValue::from_zval(val).expect("php vm broke"));
+    }
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use ext_php_rs::{convert::IntoZvalDyn, types::ZendHashTable};
+
+use crate::loader::{
+    ast::{Import, ImportTarget},
+    expression::ast::{Expression, NamedArg},
+};
+
+use super::{
+    arguments::{self, Param},
+    environment::{Env, MacroRef},
+    escape,
+    expressions::Evaluate,
+    value::{SafeFor, TaggedValue},
+    Renderable,
+};
+
+/// Binds the macros of an `{% import %}` or `{% from ... import %}` in the current scope.
+pub fn import(import: &Import, env: &mut Env) -> Result<()> {
+    let template = match &import.template {
+        Expression::Var(name) if name == "_self" => match env.get_import("_self") {
+            Some(MacroRef::Template(template)) => template.clone(),
+            _ => return Err(anyhow!("_self is not available here")),
+        },
+        expr => expr.eval(env)?.to_string(),
+    };
+
+    match &import.target {
+        ImportTarget::Alias(alias) => env.import(alias, MacroRef::Template(template)),
+        ImportTarget::Macros(names) => {
+            for (name, alias) in names {
+                let name = name.clone();
+                let template = template.clone();
+                env.import(alias, MacroRef::Macro { template, name });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Calls the macro `name` defined in `template`. It renders into an environment of its own,
+/// which only holds the arguments, with arguments beyond the macro's parameters in `varargs`.
+/// The output is safe markup.
+pub fn call_macro(
+    template: &str,
+    name: &str,
+    args: &[Expression],
+    named: &[NamedArg],
+    env: &Env,
+) -> Result<TaggedValue> {
+    let mac = env
+        .load_macro(template, name)?
+        .ok_or_else(|| anyhow!("macro {} is not defined in {}", name, template))?;
+
+    let args = args
+        .iter()
+        .map(|arg| arg.eval(env))
+        .collect::<Result<Vec<TaggedValue>>>()?;
+    let named = named
+        .iter()
+        .map(|arg| Ok((arg.name.clone(), arg.val.eval(env)?)))
+        .collect::<Result<Vec<(String, TaggedValue)>>>()?;
+
+    let mut macro_env = env.isolated();
+    macro_env.set_autoescape(escape::default_strategy(template));
+    let defaults = mac
+        .params
+        .iter()
+        .map(|param| match &param.default {
+            Some(default) => default.eval(&macro_env),
+            None => Ok(TaggedValue::Null),
+        })
+        .collect::<Result<Vec<TaggedValue>>>()?;
+    let signature = || {
+        Ok(mac
+            .params
+            .iter()
+            .zip(&defaults)
+            .map(|(param, default)| Param {
+                name: param.name.clone(),
+                default: Some(default.clone()),
+            })
+            .collect())
+    };
+
+    let mut values = arguments::bind(args, &named, 0, signature)?.into_iter();
+    for (param, default) in mac.params.iter().zip(defaults.iter()) {
+        let val = values.next().unwrap_or_else(|| default.clone());
+        macro_env.set_local(&param.name, val);
+    }
+    let mut varargs = ZendHashTable::new();
+    for val in values {
+        varargs.push(val).map_err(|err| anyhow!("{:?}", err))?;
+    }
+    let varargs = varargs.as_zval(false).map_err(|err| anyhow!("{:?}", err))?;
+    macro_env.set_local("varargs", TaggedValue::Zval(varargs));
+
+    let mut out = String::new();
+    mac.contents.render(&mut out, macro_env)?;
+    Ok(TaggedValue::Safe(Box::new(out.into()), SafeFor::All))
+}
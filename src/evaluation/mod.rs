@@ -1,13 +1,25 @@
+mod arguments;
+mod attribute;
 pub mod config;
 pub mod environment;
+mod escape;
 mod expressions;
+mod filters;
+mod functions;
+mod includes;
+mod macros;
+mod twig_tests;
 mod value;
 use std::{collections::HashMap, fmt::Write};
 
-use ext_php_rs::convert::FromZval;
+use ext_php_rs::{
+    convert::{FromZval, IntoZval, IntoZvalDyn},
+    types::{ZendCallable, ZendHashTable, ZendObject, Zval},
+    zend::ClassEntry,
+};
 
 use crate::{
-    evaluation::expressions::Evaluate,
+    evaluation::expressions::{apply_filter, Evaluate},
     loader::{
         ast::{Block, BlockType, Content, Contents, IterationType, Stmt, Template},
         expression::ast::Expression,
@@ -18,11 +30,23 @@ use crate::{
 use anyhow::{anyhow, Context, Result};
 
 use self::environment::Env;
-use self::value::TaggedValue;
+use self::value::{SafeFor, TaggedValue};
 
-pub fn render(mut tpl: Module, mut env: Env) -> Result<String> {
-    let mut block_extensions: HashMap<String, Box<Block>> = HashMap::default();
+pub fn render(tpl: Module, env: Env) -> Result<String> {
+    let base = resolve(tpl, HashMap::default(), &env)?;
+    let mut out_buf = String::default();
+    base.render(&mut out_buf, env)?;
+    Ok(out_buf)
+}
 
+/// Follows `tpl` up to the template it extends, overriding that template's blocks with those
+/// of every extension on the way and with `block_extensions`, which take precedence. Parents are
+/// evaluated in `env`.
+fn resolve(
+    mut tpl: Module,
+    mut block_extensions: HashMap<String, Box<Block>>,
+    env: &Env,
+) -> Result<Template> {
     while let Module::Extension(Extension { parent, blocks, .. }) = tpl {
         for (name, block) in blocks.into_iter() {
             match block_extensions.get_mut(&name) {
@@ -32,15 +56,15 @@ pub fn render(mut tpl: Module, mut env: Env) -> Result<String> {
                 Some(child_block) => child_block.set_parents(block),
             }
         }
-        tpl = env.load_file(parent)?;
+        let parent = parent.eval(env)?;
+        tpl = includes::load(&parent, false, env)?
+            .ok_or_else(|| anyhow!("unable to find template {}", parent))?;
     }
 
     match tpl {
         Module::Template(mut base) => {
-            let mut out_buf = String::default();
             base.apply_extensions(block_extensions);
-            base.render(&mut out_buf, env)?;
-            Ok(out_buf)
+            Ok(base)
         }
         _ => unreachable!(),
     }
@@ -51,7 +75,9 @@ trait Renderable {
 }
 
 impl Renderable for Template {
-    fn render<T: Write>(&self, out: &mut T, env: Env) -> Result<Env> {
+    fn render<T: Write>(&self, out: &mut T, mut env: Env) -> Result<Env> {
+        env.set_autoescape(escape::default_strategy(&self.name));
+        env.set_blocks(self.block_table());
         self.content.render(out, env)
     }
 }
@@ -79,48 +105,199 @@ impl Renderable for Content {
                 env.apply_setter(setter);
                 Ok(env)
             }
-            Content::Statement(Setter) => Ok(env),
+            Content::Statement(Stmt::Import(import)) => {
+                macros::import(import, &mut env)?;
+                Ok(env)
+            }
+            Content::Statement(Stmt::Include(include)) => includes::include(include, out, env),
         }
     }
 }
 
 impl Renderable for Expression {
     fn render<T: Write>(&self, out: &mut T, env: Env) -> Result<Env> {
-        write!(out, "{}", self.eval(&env)?)?;
+        let val = self.eval(&env)?;
+        write_escaped(out, &val, &env)?;
         Ok(env)
     }
 }
 
+/// Writes `val` escaped with the active autoescaping strategy, unless it is safe for it.
+fn write_escaped<T: Write>(out: &mut T, val: &TaggedValue, env: &Env) -> Result<()> {
+    match env.autoescape() {
+        Some(strategy) if !val.is_safe_for(strategy) => {
+            write!(out, "{}", escape::escape_value(val, strategy)?)?
+        }
+        _ => write!(out, "{}", val)?,
+    }
+    Ok(())
+}
+
 impl Renderable for Block {
     fn render<T: Write>(&self, out: &mut T, env: Env) -> Result<Env> {
-        let mut env = env.enter_new_scope();
         match &self.typ {
-            BlockType::BlockName(_) => self.contents.render(out, env).map(Env::exit_scope),
+            BlockType::BlockName(_) => {
+                let mut env = env.enter_new_scope();
+                let outer = env.set_parent_block(self.parent.clone());
+                let mut env = self.contents.render(out, env)?.exit_scope();
+                env.set_parent_block(outer);
+                Ok(env)
+            }
             BlockType::Loop(l) => {
-                let zv = if let TaggedValue::Zval(zv) = env.get(&l.iterator)? {
-                    zv
-                } else {
-                    return Err(anyhow!("variable {} is not iterable", &l.iterator));
-                };
+                let zv = ensure_traversable(l.iterator.eval(&env)?)?;
                 let collection = zv
                     .array()
-                    .with_context(|| format!("variable {}, is not iterable", &l.iterator))?;
+                    .with_context(|| format!("{:?} is not iterable", &l.iterator))?;
 
+                let parent = env.context()?;
+                let mut env = env;
+                let mut items = Vec::with_capacity(collection.len());
                 for (idx, key, val) in collection.iter() {
-                    match &l.typ {
-                        IterationType::SingleVal(name) => {
-                            env.set(name, TaggedValue::from_zval(val).expect("php vm broke"))
-                        }
-                        IterationType::KeyVal((kname, vname)) => {
-                            env.set(kname, key.map_or_else(|| idx.into(), TaggedValue::from));
-                            env.set(vname, TaggedValue::from_zval(val).expect("php vm broke"));
+                    let key = key.map_or_else(|| idx.into(), TaggedValue::from);
+                    let val = TaggedValue::from_zval(val).expect("php vm broke");
+                    if let Some(condition) = &l.condition {
+                        env = env.enter_new_scope();
+                        bind_iteration(&l.typ, &mut env, key.clone(), val.clone());
+                        let keep = condition.eval(&env)?.is_truthy();
+                        env = env.exit_scope();
+                        if !keep {
+                            continue;
                         }
+                    }
+                    items.push((key, val));
+                }
+
+                if items.is_empty() {
+                    return match &l.else_branch {
+                        Some(contents) => contents.render(out, env),
+                        None => Ok(env),
                     };
+                }
 
-                    env = self.contents.render(out, env)?
+                let length = items.len();
+                for (n, (key, val)) in items.into_iter().enumerate() {
+                    env = env.enter_new_scope();
+                    env.set_local("loop", loop_variable(n, length, &parent)?);
+                    bind_iteration(&l.typ, &mut env, key, val);
+                    env = self.contents.render(out, env)?.exit_scope();
                 }
                 Ok(env)
             }
+            BlockType::AutoEscape(strategy) => {
+                let strategy = strategy.as_deref().map(str::parse).transpose()?;
+                let mut env = env;
+                let previous = env.set_autoescape(strategy);
+                env = self.contents.render(out, env)?;
+                env.set_autoescape(previous);
+                Ok(env)
+            }
+            // the rendered contents are safe, what the filters make of them is printed like any
+            // other expression
+            BlockType::Apply(filters) => {
+                let mut buf = String::new();
+                let env = self.contents.render(&mut buf, env)?;
+                let mut val = TaggedValue::Safe(Box::new(buf.into()), SafeFor::All);
+                for filter in filters {
+                    val = apply_filter(&filter.name, val, &filter.params, &filter.named, &env)?;
+                }
+                write_escaped(out, &val, &env)?;
+                Ok(env)
+            }
+            BlockType::Embed(include) => includes::embed(include, &self.contents, out, env),
+            BlockType::Conditional(cond) => {
+                for branch in cond.branches.iter() {
+                    if branch.condition.eval(&env)?.is_truthy() {
+                        return branch.contents.render(out, env);
+                    }
+                }
+                match &cond.else_branch {
+                    Some(contents) => contents.render(out, env),
+                    None => Ok(env),
+                }
+            }
+        }
+    }
+}
+
+fn bind_iteration(typ: &IterationType, env: &mut Env, key: TaggedValue, val: TaggedValue) {
+    match typ {
+        IterationType::SingleVal(name) => env.set_local(name, val),
+        IterationType::KeyVal((kname, vname)) => {
+            env.set_local(kname, key);
+            env.set_local(vname, val);
         }
     }
 }
+
+/// Calls a function from PHP's global function table.
+pub(crate) fn call_php(name: &str, params: Vec<&dyn IntoZvalDyn>) -> Result<Zval> {
+    ZendCallable::try_from_name(name)
+        .map_err(|err| anyhow!("{}", err))?
+        .try_call(params)
+        .map_err(|err| anyhow!("{}", err))
+}
+
+/// Whether `val` is an instance of `class`, or of a class extending or implementing it.
+pub(crate) fn is_a(val: &TaggedValue, class: &str) -> Result<bool> {
+    Ok(call_php("is_a", vec![val, &TaggedValue::from(class)])?.bool() == Some(true))
+}
+
+/// Calls `method` on the PHP object `obj`.
+pub(crate) fn call_method(obj: &Zval, method: &str, params: Vec<&dyn IntoZvalDyn>) -> Result<Zval> {
+    let callable = config::build_callable(obj, method);
+    ZendCallable::new(&callable)
+        .map_err(|err| anyhow!("{}", err))?
+        .try_call(params)
+        .map_err(|err| anyhow!("{}", err))
+}
+
+/// Instantiates the PHP class `class` with the constructor arguments `params`, `None` if no
+/// such class is loaded.
+pub(crate) fn new_object(class: &str, params: Vec<&dyn IntoZvalDyn>) -> Result<Option<Zval>> {
+    let Some(class) = ClassEntry::try_find(class) else {
+        return Ok(None);
+    };
+    let obj = ZendObject::new(class)
+        .into_zval(false)
+        .map_err(|err| anyhow!("{}", err))?;
+    call_method(&obj, "__construct", params)?;
+    Ok(Some(obj))
+}
+
+/// Mirrors Twig's `twig_ensure_traversable`: arrays are iterated as is, `Traversable` objects
+/// are converted with `iterator_to_array` and anything else iterates over nothing.
+pub(crate) fn ensure_traversable(val: TaggedValue) -> Result<Zval> {
+    match val {
+        TaggedValue::Safe(val, _) => ensure_traversable(*val),
+        TaggedValue::Zval(zv) if zv.is_array() => Ok(zv),
+        TaggedValue::Zval(zv) if zv.is_object() => {
+            let obj = TaggedValue::Zval(zv);
+            if !is_a(&obj, "Traversable")? {
+                return ensure_traversable(TaggedValue::Null);
+            }
+            call_php("iterator_to_array", vec![&obj])
+                .map_err(|err| anyhow!("object is not traversable: {}", err))
+        }
+        _ => ZendHashTable::new()
+            .into_zval(false)
+            .map_err(|err| anyhow!("{:?}", err)),
+    }
+}
+
+fn loop_variable(n: usize, length: usize, parent: &Zval) -> Result<TaggedValue> {
+    let (n, length) = (n as i64, length as i64);
+    let mut lv = ZendHashTable::new();
+    let mut insert =
+        |key: &str, val: TaggedValue| lv.insert(key, val).map_err(|err| anyhow!("{:?}", err));
+    insert("index", TaggedValue::Number(n + 1))?;
+    insert("index0", TaggedValue::Number(n))?;
+    insert("revindex", TaggedValue::Number(length - n))?;
+    insert("revindex0", TaggedValue::Number(length - n - 1))?;
+    insert("first", TaggedValue::Bool(n == 0))?;
+    insert("last", TaggedValue::Bool(n + 1 == length))?;
+    insert("length", TaggedValue::Number(length))?;
+    insert("parent", TaggedValue::Zval(parent.shallow_clone()))?;
+    lv.into_zval(false)
+        .map(TaggedValue::Zval)
+        .map_err(|err| anyhow!("{:?}", err))
+}
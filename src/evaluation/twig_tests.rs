@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+
+use super::{
+    call_php, ensure_traversable,
+    value::{Scalar, TaggedValue},
+};
+
+/// A built-in test, called with the tested value followed by the test arguments.
+pub type Test = fn(&[TaggedValue]) -> Result<bool>;
+
+/// Twig's core tests except `defined`, which needs the unevaluated expression.
+pub fn builtin(name: &str) -> Option<Test> {
+    match name {
+        "null" | "none" => Some(null),
+        "empty" => Some(empty),
+        "even" => Some(even),
+        "odd" => Some(odd),
+        "iterable" => Some(iterable),
+        "same as" => Some(same_as),
+        "divisible by" => Some(divisible_by),
+        "constant" => Some(constant),
+        _ => None,
+    }
+}
+
+fn null(params: &[TaggedValue]) -> Result<bool> {
+    match params {
+        [val] => Ok(val.is_null()),
+        _ => Err(anyhow!("null test takes no arguments")),
+    }
+}
+
+fn empty(params: &[TaggedValue]) -> Result<bool> {
+    match params {
+        [val] => is_empty(val),
+        _ => Err(anyhow!("empty test takes no arguments")),
+    }
+}
+
+/// Mirrors `twig_test_empty`, also used by the `default` filter.
+pub fn is_empty(val: &TaggedValue) -> Result<bool> {
+    match val.scalar() {
+        Scalar::Null => Ok(true),
+        Scalar::Bool(b) => Ok(!b),
+        Scalar::Int(_) | Scalar::Float(_) => Ok(false),
+        Scalar::Str(s) => Ok(s.is_empty()),
+        Scalar::Array(arr) => Ok(arr.is_empty()),
+        Scalar::Object(_) => {
+            if call_php("is_countable", vec![val])?.bool() == Some(true) {
+                return Ok(call_php("count", vec![val])?.long() == Some(0));
+            }
+            if call_php("is_iterable", vec![val])?.bool() == Some(true) {
+                let arr = ensure_traversable(val.clone())?;
+                return Ok(arr.array().map_or(0, |arr| arr.len()) == 0);
+            }
+            let to_string = TaggedValue::from("__toString");
+            if call_php("method_exists", vec![val, &to_string])?.bool() == Some(true) {
+                return Ok(call_php("strval", vec![val])?.str() == Some(""));
+            }
+            Ok(false)
+        }
+    }
+}
+
+fn even(params: &[TaggedValue]) -> Result<bool> {
+    match params {
+        [val] => Ok(to_int(val)? % 2 == 0),
+        _ => Err(anyhow!("even test takes no arguments")),
+    }
+}
+
+fn odd(params: &[TaggedValue]) -> Result<bool> {
+    match params {
+        [val] => Ok(to_int(val)? % 2 != 0),
+        _ => Err(anyhow!("odd test takes no arguments")),
+    }
+}
+
+fn iterable(params: &[TaggedValue]) -> Result<bool> {
+    let [val] = params else {
+        return Err(anyhow!("iterable test takes no arguments"));
+    };
+    match val.scalar() {
+        Scalar::Array(_) => Ok(true),
+        Scalar::Object(_) => Ok(call_php("is_iterable", vec![val])?.bool() == Some(true)),
+        _ => Ok(false),
+    }
+}
+
+fn same_as(params: &[TaggedValue]) -> Result<bool> {
+    match params {
+        [val, other] => Ok(val.identical(other)),
+        _ => Err(anyhow!("same as test takes exactly one argument")),
+    }
+}
+
+fn divisible_by(params: &[TaggedValue]) -> Result<bool> {
+    match params {
+        [val, num] => match to_int(num)? {
+            0 => Err(anyhow!("modulo by zero")),
+            num => Ok(to_int(val)?.checked_rem(num).unwrap_or(0) == 0),
+        },
+        _ => Err(anyhow!("divisible by test takes exactly one argument")),
+    }
+}
+
+fn constant(params: &[TaggedValue]) -> Result<bool> {
+    match params {
+        [val, name] => {
+            let constant = TaggedValue::Zval(call_php("constant", vec![name])?);
+            Ok(val.identical(&constant))
+        }
+        _ => Err(anyhow!("constant test takes exactly one argument")),
+    }
+}
+
+fn to_int(val: &TaggedValue) -> Result<i64> {
+    val.to_number()
+        .map(|num| num.as_i64())
+        .map_err(|err| anyhow!(err))
+}
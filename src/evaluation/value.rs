@@ -1,11 +1,13 @@
-use std::fmt::Display;
+use std::{borrow::Cow, cmp::Ordering, fmt::Display};
 
 use ext_php_rs::{
     convert::{FromZval, IntoZval},
     flags::DataType,
-    types::Zval,
+    types::{ZendHashTable, ZendObject, Zval},
 };
 use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+use super::escape::Strategy;
 #[derive(Debug)]
 pub enum TaggedValue {
     Str(String),
@@ -14,6 +16,359 @@ pub enum TaggedValue {
     Number(i64),
     Float(f64),
     Bool(bool),
+    Null,
+    /// A value autoescaping must leave alone, e.g. the result of `|raw` or of a filter
+    /// marked `is_safe`.
+    Safe(Box<TaggedValue>, SafeFor),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SafeFor {
+    All,
+    Strategies(Vec<Strategy>),
+}
+
+/// A borrowed view of a value with PHP's types, used to implement its type juggling rules.
+pub enum Scalar<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Cow<'a, str>),
+    Array(&'a ZendHashTable),
+    Object(&'a ZendObject),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl TaggedValue {
+    /// Twig truthiness: empty strings, "0", zero, empty arrays and null are falsy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Self::Str(s) => !s.is_empty() && s != "0",
+            Self::Usize(us) => *us != 0,
+            Self::Number(n) => *n != 0,
+            Self::Float(fl) => *fl != 0.0,
+            Self::Bool(b) => *b,
+            Self::Null => false,
+            Self::Zval(zv) => Scalar::from(zv).is_truthy(),
+            Self::Safe(val, _) => val.is_truthy(),
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        match self {
+            Self::Null => true,
+            Self::Zval(zv) => zv.is_null() || matches!(zv.get_type(), DataType::Undef),
+            Self::Safe(val, _) => val.is_null(),
+            _ => false,
+        }
+    }
+
+    pub fn is_safe_for(&self, strategy: Strategy) -> bool {
+        match self {
+            Self::Safe(_, SafeFor::All) => true,
+            // like Twig, output escaped for attributes is considered safe HTML as well
+            Self::Safe(_, SafeFor::Strategies(strategies)) => {
+                strategies.contains(&strategy)
+                    || (strategy == Strategy::Html && strategies.contains(&Strategy::HtmlAttr))
+            }
+            _ => false,
+        }
+    }
+
+    /// The value without its safe marker.
+    pub fn unwrap_safe(&self) -> &TaggedValue {
+        match self {
+            Self::Safe(val, _) => val.unwrap_safe(),
+            val => val,
+        }
+    }
+
+    pub fn scalar(&self) -> Scalar<'_> {
+        match self {
+            Self::Str(s) => Scalar::Str(Cow::Borrowed(s)),
+            Self::Usize(us) => i64::try_from(*us).map_or(Scalar::Float(*us as f64), Scalar::Int),
+            Self::Number(n) => Scalar::Int(*n),
+            Self::Float(fl) => Scalar::Float(*fl),
+            Self::Bool(b) => Scalar::Bool(*b),
+            Self::Null => Scalar::Null,
+            Self::Zval(zv) => Scalar::from(zv),
+            Self::Safe(val, _) => val.scalar(),
+        }
+    }
+
+    /// PHP 8 loose comparison (`<=>`), `None` when the operands are not comparable.
+    pub fn compare(&self, other: &TaggedValue) -> Option<Ordering> {
+        self.scalar().compare(&other.scalar())
+    }
+
+    /// A total order for sorting: PHP's comparison, falling back to the type and then the
+    /// string value for operands PHP can't compare.
+    pub fn sort_compare(&self, other: &TaggedValue) -> Ordering {
+        self.compare(other).unwrap_or_else(|| {
+            let (lhs, rhs) = (self.scalar(), other.scalar());
+            lhs.type_rank()
+                .cmp(&rhs.type_rank())
+                .then_with(|| self.to_string().cmp(&other.to_string()))
+        })
+    }
+
+    pub fn loose_eq(&self, other: &TaggedValue) -> bool {
+        self.compare(other) == Some(Ordering::Equal)
+    }
+
+    /// PHP's `===`.
+    pub fn identical(&self, other: &TaggedValue) -> bool {
+        self.scalar().identical(&other.scalar())
+    }
+
+    /// Numeric value for arithmetic, rejecting values PHP refuses as operands.
+    pub fn to_number(&self) -> Result<Number, String> {
+        match self.scalar() {
+            Scalar::Null => Ok(Number::Int(0)),
+            Scalar::Bool(b) => Ok(Number::Int(b as i64)),
+            Scalar::Int(n) => Ok(Number::Int(n)),
+            Scalar::Float(fl) => Ok(Number::Float(fl)),
+            Scalar::Str(s) => leading_number(&s).ok_or(format!("non-numeric value \"{}\"", s)),
+            Scalar::Array(_) => Err("unsupported operand type array".to_string()),
+            Scalar::Object(_) => Err("unsupported operand type object".to_string()),
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Number(n) => Some(*n),
+            Self::Usize(us) => i64::try_from(*us).ok(),
+            Self::Zval(zv) => zv.long(),
+            Self::Safe(val, _) => val.as_int(),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<&'a Zval> for Scalar<'a> {
+    fn from(zv: &'a Zval) -> Self {
+        match zv {
+            val if val.is_bool() => Scalar::Bool(val.bool().unwrap_or_default()),
+            val if val.is_long() => Scalar::Int(val.long().unwrap_or_default()),
+            val if val.is_double() => Scalar::Float(val.double().unwrap_or_default()),
+            val if val.is_string() => Scalar::Str(Cow::Borrowed(val.str().unwrap_or_default())),
+            val if val.is_array() => val.array().map_or(Scalar::Null, Scalar::Array),
+            val if val.is_object() => val.object().map_or(Scalar::Null, Scalar::Object),
+            _ => Scalar::Null,
+        }
+    }
+}
+
+impl Scalar<'_> {
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Scalar::Null => false,
+            Scalar::Bool(b) => *b,
+            Scalar::Int(n) => *n != 0,
+            Scalar::Float(fl) => *fl != 0.0,
+            Scalar::Str(s) => !s.is_empty() && s != "0",
+            Scalar::Array(arr) => !arr.is_empty(),
+            Scalar::Object(_) => true,
+        }
+    }
+
+    pub fn identical(&self, other: &Scalar) -> bool {
+        match (self, other) {
+            (Scalar::Null, Scalar::Null) => true,
+            (Scalar::Bool(lhs), Scalar::Bool(rhs)) => lhs == rhs,
+            (Scalar::Int(lhs), Scalar::Int(rhs)) => lhs == rhs,
+            (Scalar::Float(lhs), Scalar::Float(rhs)) => lhs == rhs,
+            (Scalar::Str(lhs), Scalar::Str(rhs)) => lhs == rhs,
+            (Scalar::Array(lhs), Scalar::Array(rhs)) => {
+                lhs.len() == rhs.len()
+                    && lhs.iter().zip(rhs.iter()).all(|(lhs, rhs)| {
+                        let ((l_idx, l_key, l_val), (r_idx, r_key, r_val)) = (lhs, rhs);
+                        l_key == r_key
+                            && (l_key.is_some() || l_idx == r_idx)
+                            && Scalar::from(l_val).identical(&Scalar::from(r_val))
+                    })
+            }
+            (Scalar::Object(lhs), Scalar::Object(rhs)) => std::ptr::eq(*lhs, *rhs),
+            _ => false,
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            Scalar::Null => 0,
+            Scalar::Bool(_) => 1,
+            Scalar::Int(_) | Scalar::Float(_) => 2,
+            Scalar::Str(_) => 3,
+            Scalar::Array(_) => 4,
+            Scalar::Object(_) => 5,
+        }
+    }
+
+    fn number(&self) -> Option<Number> {
+        match self {
+            Scalar::Int(n) => Some(Number::Int(*n)),
+            Scalar::Float(fl) => Some(Number::Float(*fl)),
+            _ => None,
+        }
+    }
+
+    /// Follows PHP 8's table at https://www.php.net/manual/en/language.operators.comparison.php
+    pub fn compare(&self, other: &Scalar) -> Option<Ordering> {
+        match (self, other) {
+            (Scalar::Null, Scalar::Str(s)) => Some("".cmp(s.as_ref())),
+            (Scalar::Str(s), Scalar::Null) => Some(s.as_ref().cmp("")),
+            (Scalar::Null | Scalar::Bool(_), _) | (_, Scalar::Null | Scalar::Bool(_)) => {
+                Some(self.is_truthy().cmp(&other.is_truthy()))
+            }
+            (Scalar::Str(lhs), Scalar::Str(rhs)) => match (numeric(lhs), numeric(rhs)) {
+                (Some(lhs), Some(rhs)) => lhs.compare(&rhs),
+                _ => Some(lhs.cmp(rhs)),
+            },
+            (Scalar::Str(s), num @ (Scalar::Int(_) | Scalar::Float(_))) => {
+                compare_num_str(num, s).map(Ordering::reverse)
+            }
+            (num @ (Scalar::Int(_) | Scalar::Float(_)), Scalar::Str(s)) => compare_num_str(num, s),
+            (Scalar::Array(lhs), Scalar::Array(rhs)) => compare_arrays(lhs, rhs),
+            (Scalar::Array(_), _) => Some(Ordering::Greater),
+            (_, Scalar::Array(_)) => Some(Ordering::Less),
+            (Scalar::Object(_), _) | (_, Scalar::Object(_)) => None,
+            (lhs, rhs) => lhs.number()?.compare(&rhs.number()?),
+        }
+    }
+}
+
+fn compare_num_str(num: &Scalar, s: &str) -> Option<Ordering> {
+    let num = num.number()?;
+    match numeric(s) {
+        Some(other) => num.compare(&other),
+        None => Some(num.to_string().as_str().cmp(s)),
+    }
+}
+
+fn compare_arrays(lhs: &ZendHashTable, rhs: &ZendHashTable) -> Option<Ordering> {
+    if lhs.len() != rhs.len() {
+        return Some(lhs.len().cmp(&rhs.len()));
+    }
+    for (idx, key, val) in lhs.iter() {
+        let other = match key {
+            Some(key) => rhs.get(&key),
+            None => rhs.get_index(idx),
+        }?;
+        match Scalar::from(val).compare(&Scalar::from(other))? {
+            Ordering::Equal => continue,
+            unequal => return Some(unequal),
+        }
+    }
+    Some(Ordering::Equal)
+}
+
+impl Number {
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Number::Int(n) => *n as f64,
+            Number::Float(fl) => *fl,
+        }
+    }
+
+    /// Integer conversion as done by PHP's integer operators, truncating floats.
+    pub fn as_i64(&self) -> i64 {
+        match self {
+            Number::Int(n) => *n,
+            Number::Float(fl) => *fl as i64,
+        }
+    }
+
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(lhs), Number::Int(rhs)) => Some(lhs.cmp(rhs)),
+            (lhs, rhs) => lhs.as_f64().partial_cmp(&rhs.as_f64()),
+        }
+    }
+}
+
+impl From<Number> for TaggedValue {
+    fn from(num: Number) -> Self {
+        match num {
+            Number::Int(n) => TaggedValue::Number(n),
+            Number::Float(fl) => TaggedValue::Float(fl),
+        }
+    }
+}
+
+impl Display for Number {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        TaggedValue::from(*self).fmt(f)
+    }
+}
+
+/// Looks up `key` the way PHP normalizes array keys: integers and canonical integer strings
+/// address numeric indices, `null` is the empty string and floats and bools are truncated.
+pub fn array_get<'a>(arr: &'a ZendHashTable, key: &Scalar) -> Option<&'a Zval> {
+    let index = match key {
+        Scalar::Int(n) => Some(*n),
+        Scalar::Float(fl) => Some(*fl as i64),
+        Scalar::Bool(b) => Some(*b as i64),
+        Scalar::Str(s) => s.parse::<i64>().ok().filter(|n| n.to_string() == *s),
+        Scalar::Null => return arr.get(""),
+        Scalar::Array(_) | Scalar::Object(_) => return None,
+    };
+    match (index, key) {
+        (Some(index), _) => arr.get_index(u64::try_from(index).ok()?),
+        (None, Scalar::Str(s)) => arr.get(s),
+        _ => None,
+    }
+}
+
+/// Parses a PHP numeric string: surrounding whitespace, sign, digits, fraction and exponent.
+pub fn numeric(s: &str) -> Option<Number> {
+    let trimmed = s.trim();
+    let valid = !trimmed.is_empty()
+        && trimmed
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E'));
+    if !valid {
+        return None;
+    }
+    match trimmed.parse::<i64>() {
+        Ok(n) => Some(Number::Int(n)),
+        Err(_) => trimmed.parse::<f64>().ok().map(Number::Float),
+    }
+}
+
+/// Like [`numeric`] but also accepts a leading number followed by garbage, e.g. `"5 apples"`.
+fn leading_number(s: &str) -> Option<Number> {
+    let trimmed = s.trim_start().as_bytes();
+    let digits = |from: usize| {
+        from + trimmed[from..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+
+    let mut end = digits(usize::from(matches!(trimmed.first(), Some(b'+' | b'-'))));
+    let mut has_digits = end > 0 && trimmed[end - 1].is_ascii_digit();
+    if trimmed.get(end) == Some(&b'.') {
+        let fraction = digits(end + 1);
+        has_digits |= fraction > end + 1;
+        end = fraction;
+    }
+    if !has_digits {
+        return None;
+    }
+    if matches!(trimmed.get(end), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(trimmed.get(end + 1), Some(b'+' | b'-')));
+        let exponent = digits(end + 1 + sign);
+        if exponent > end + 1 + sign {
+            end = exponent;
+        }
+    }
+    numeric(std::str::from_utf8(&trimmed[..end]).ok()?)
 }
 
 impl Display for TaggedValue {
@@ -24,6 +379,7 @@ impl Display for TaggedValue {
             Self::Number(n) => write!(f, "{}", n),
             Self::Bool(true) => write!(f, "{}", 1),
             Self::Bool(false) => write!(f, ""),
+            Self::Null => write!(f, ""),
             Self::Float(fl) => {
                 if let Some(dec) = Decimal::from_f64(*fl) {
                     write!(f, "{}", dec.round_dp(6).normalize())
@@ -35,8 +391,10 @@ impl Display for TaggedValue {
                 val if val.is_long() => write!(f, "{}", val.long().unwrap()),
                 val if val.is_double() => write!(f, "{}", val.double().unwrap()),
                 val if val.is_string() => write!(f, "{}", val.str().unwrap()),
+                val if val.is_true() => write!(f, "{}", 1),
                 _ => write!(f, "{}", zv.str().unwrap_or("")),
             },
+            Self::Safe(val, _) => val.fmt(f),
         }
     }
 }
@@ -49,7 +407,9 @@ impl Clone for TaggedValue {
             Self::Number(n) => Self::Number(*n),
             Self::Float(f) => Self::Float(*f),
             Self::Bool(b) => Self::Bool(*b),
+            Self::Null => Self::Null,
             Self::Zval(zv) => Self::Zval(zv.shallow_clone()),
+            Self::Safe(val, safe_for) => Self::Safe(val.clone(), safe_for.clone()),
         }
     }
 }
@@ -98,11 +458,41 @@ impl IntoZval for TaggedValue {
         match self {
             Self::Str(s) => zv.set_string(&s, persistent)?,
             Self::Number(num) => zv.set_long(num),
-            Self::Usize(num) => todo!("usize as long not yet possible"),
+            Self::Usize(num) => zv.set_long(num as i64),
             Self::Bool(b) => zv.set_bool(b),
             Self::Float(f) => zv.set_double(f),
+            Self::Null => zv.set_null(),
             Self::Zval(inner) => *zv = inner,
+            Self::Safe(val, _) => val.set_zval(zv, persistent)?,
         };
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_leading_number() {
+        let cases = [
+            ("5 apples", Some("5")),
+            ("  -12.5e3kg", Some("-12500")),
+            ("+.5", Some("0.5")),
+            ("7.", Some("7")),
+            ("1e", Some("1")),
+            ("2E-2x", Some("0.02")),
+            ("-", None),
+            (".e5", None),
+            ("apples", None),
+        ];
+        for (input, expected) in cases {
+            let number = leading_number(input).map(|n| n.to_string());
+            assert_eq!(number.as_deref(), expected, "{}", input);
+        }
+
+        let long = "1".repeat(10_000) + "x";
+        assert!(matches!(leading_number(&long), Some(Number::Float(_))));
+        assert!(leading_number(&"x".repeat(100_000)).is_none());
+    }
+}
@@ -14,8 +14,12 @@ pub fn render(
     template: &str,
     data: &mut Zval,
     twig_env: &mut Zval,
+    lookup_order: Option<String>,
 ) -> Result<String> {
-    let conf = Config::new(twig_env.shallow_clone());
+    let mut conf = Config::new(twig_env.shallow_clone());
+    if let Some(lookup_order) = lookup_order {
+        conf = conf.with_lookup_order(lookup_order.parse()?);
+    }
     let base_dir = PathBuf::from(base_dir);
     let mut loader = Loader::new(base_dir);
     let tpl = loader.load(template)?;
@@ -1,6 +1,6 @@
 use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use super::expression::ast::Expression;
+use super::expression::ast::{Expression, FuncCall};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Module {
@@ -12,17 +12,52 @@ pub enum Module {
 pub struct Template {
     pub name: String,
     pub content: Contents,
+    pub macros: Macros,
+    pub uses: Vec<Use>,
+    /// Blocks the template doesn't render in place: those imported with `use` it doesn't define
+    /// itself, and those of extensions it has no counterpart for.
+    pub blocks: HashMap<String, Box<Block>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Extension {
     pub name: String,
-    pub parent: String,
+    /// The template extended, possibly a list of candidates, as evaluated when rendering.
+    pub parent: Expression,
     pub blocks: HashMap<String, Box<Block>>,
+    pub macros: Macros,
+    pub uses: Vec<Use>,
 }
 
 pub type Contents = Vec<Content>;
 
+/// `{% use 'blocks.twig' with sidebar as base_sidebar %}`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Use {
+    pub template: String,
+    /// Blocks imported under another name, as (block name, alias) pairs.
+    pub renames: Vec<(String, String)>,
+}
+
+/// Shared with the loader's cache, so calling a macro doesn't copy its template.
+pub type Macros = HashMap<String, Rc<Macro>>;
+
+/// `{% macro name(params) %}...{% endmacro %}`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<MacroParam>,
+    pub contents: Contents,
+}
+
+/// A macro parameter, `name` or `name = default`. Parameters without a default are null when
+/// left out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MacroParam {
+    pub name: String,
+    pub default: Option<Expression>,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Content {
     Text(String),
@@ -35,18 +70,42 @@ pub enum Content {
 pub struct Block {
     pub typ: BlockType,
     pub contents: Contents,
+    /// The block this one overrides, rendered by `parent()`.
+    pub parent: Option<Rc<Block>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum BlockType {
     BlockName(String),
     Loop(Loop),
+    Conditional(Conditional),
+    /// `{% autoescape %}` with its escaping strategy, `None` for `{% autoescape false %}`.
+    AutoEscape(Option<String>),
+    /// `{% apply %}` (or the older `{% filter %}`) with the filters its rendered contents go
+    /// through, in order.
+    Apply(Vec<FuncCall>),
+    /// `{% embed %}`, the contents being the blocks overriding those of the embedded template.
+    Embed(Include),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Loop {
     pub typ: IterationType,
-    pub iterator: String,
+    pub iterator: Expression,
+    pub condition: Option<Expression>,
+    pub else_branch: Option<Contents>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Conditional {
+    pub branches: Vec<Branch>,
+    pub else_branch: Option<Contents>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Branch {
+    pub condition: Expression,
+    pub contents: Contents,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -58,7 +117,34 @@ pub enum IterationType {
 #[derive(Debug, PartialEq, Clone)]
 pub enum Stmt {
     Set(Setter),
-    Include(String),
+    Include(Include),
+    Import(Import),
+}
+
+/// `{% import 'forms.twig' as forms %}` or `{% from _self import input as field %}`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Import {
+    pub template: Expression,
+    pub target: ImportTarget,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ImportTarget {
+    /// All macros of the template, called as `alias.name()`.
+    Alias(String),
+    /// Single macros, each with the name it is called by.
+    Macros(Vec<(String, String)>),
+}
+
+/// The template an `include` or `embed` tag renders and the variables it renders it with.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Include {
+    pub template: Expression,
+    /// `with {...}`, variables added to (or with `only`, replacing) the current ones.
+    pub vars: Option<Expression>,
+    pub only: bool,
+    /// `ignore missing`, renders nothing when the template doesn't exist.
+    pub ignore_missing: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -68,56 +154,150 @@ pub struct Setter {
 }
 
 impl Template {
-    pub fn replace_includes(mut self, replace: &mut dyn FnMut(Content) -> Content) -> Template {
-        self.content = self
-            .content
-            .into_iter()
-            .map(|c| replace_includes(c, replace))
-            .collect();
-        self
+    pub fn apply_extensions(&mut self, mut extensions: HashMap<String, Box<Block>>) {
+        extend_blocks(&mut self.content, &mut extensions);
+        self.blocks.extend(extensions);
     }
 
-    pub fn into_block(self) -> Content {
-        let Self { name, content } = self;
-        Content::Block(Box::new(Block {
-            typ: BlockType::BlockName(name),
-            contents: content,
-        }))
+    /// Every block of the template by name, nested ones included.
+    pub fn block_table(&self) -> HashMap<String, Box<Block>> {
+        let mut table = HashMap::default();
+        collect_blocks(&self.content, &mut table);
+        for (name, block) in self.blocks.iter() {
+            table.entry(name.clone()).or_insert_with(|| block.clone());
+        }
+        table
     }
 
-    pub fn apply_extensions(&mut self, mut extensions: HashMap<String, Box<Block>>) {
-        extend_blocks(&mut self.content, &mut extensions);
+    /// Adds blocks imported with `use`. Blocks the template defines take precedence, with the
+    /// imported block as their parent.
+    pub fn use_blocks(&mut self, mut used: HashMap<String, Box<Block>>) {
+        use_parents(&mut self.content, &mut used);
+        for (name, block) in used {
+            self.blocks.entry(name).or_insert(block);
+        }
+    }
+
+    /// The blocks `use` imports from this template, those imported into it included.
+    pub fn traits(&self) -> HashMap<String, Box<Block>> {
+        get_blocks(self.content.clone(), self.blocks.clone())
     }
 }
 
-fn replace_includes(content: Content, replace: &mut dyn FnMut(Content) -> Content) -> Content {
-    match content {
-        Content::Statement(Stmt::Include(_)) => replace(content),
-        Content::Block(mut block) => {
-            block.contents = block
-                .contents
-                .into_iter()
-                .map(|c| replace_includes(c, replace))
-                .collect();
-            Content::Block(block)
+impl Extension {
+    /// Adds blocks imported with `use`, overriding the parent's. Blocks the extension defines
+    /// take precedence, with the imported block as their parent.
+    pub fn use_blocks(&mut self, used: HashMap<String, Box<Block>>) {
+        for (name, block) in used {
+            match self.blocks.get_mut(&name) {
+                Some(own) => own.set_parents(block),
+                None => {
+                    self.blocks.insert(name, block);
+                }
+            }
+        }
+    }
+}
+
+/// Collects the blocks in `content` by name, including those only found in the blocks they
+/// override. The first block found with a name wins.
+fn collect_blocks(content: &Contents, table: &mut HashMap<String, Box<Block>>) {
+    for elem in content.iter() {
+        if let Content::Block(block) = elem {
+            if block.is_embed() {
+                continue;
+            }
+            let mut current = Some(block.as_ref());
+            while let Some(block) = current {
+                if let Some(name) = block.get_name() {
+                    table
+                        .entry(name.to_string())
+                        .or_insert_with(|| Box::new(block.clone()));
+                }
+                for contents in block.contents() {
+                    collect_blocks(contents, table);
+                }
+                current = block.parent.as_deref();
+            }
+        }
+    }
+}
+
+fn link_nested(content: &mut Contents, parents: &mut HashMap<String, Box<Block>>) {
+    for elem in content.iter_mut() {
+        if let Content::Block(ref mut block) = elem {
+            if block.is_embed() {
+                continue;
+            }
+            match block.get_name().and_then(|name| parents.remove(name)) {
+                Some(parent) => block.set_parents(parent),
+                None => {
+                    for contents in block.contents_mut() {
+                        link_nested(contents, parents);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn use_parents(content: &mut Contents, used: &mut HashMap<String, Box<Block>>) {
+    for elem in content.iter_mut() {
+        if let Content::Block(ref mut block) = elem {
+            if block.is_embed() {
+                continue;
+            }
+            for contents in block.contents_mut() {
+                use_parents(contents, used);
+            }
+            if let Some(parent) = block.get_name().and_then(|name| used.remove(name)) {
+                block.set_parents(parent);
+            }
         }
-        _ => content,
     }
 }
 
 fn extend_blocks(content: &mut Contents, extensions: &mut HashMap<String, Box<Block>>) {
     for elem in content.iter_mut() {
         if let Content::Block(ref mut base) = elem {
+            if base.is_embed() {
+                continue;
+            }
             if let Some(child) = base.get_name().and_then(|name| extensions.remove(name)) {
                 let parent = std::mem::replace(base, child);
                 base.set_parents(parent)
             }
-            extend_blocks(&mut base.contents, extensions);
+            // Blocks nested in an overridden block still show up when it's rendered through
+            // `parent()`, so they get overridden too.
+            let mut current = Some(base.as_mut());
+            while let Some(block) = current {
+                for contents in block.contents_mut() {
+                    extend_blocks(contents, extensions);
+                }
+                current = block.parent.as_mut().map(Rc::make_mut);
+            }
+        }
+    }
+}
+
+impl Module {
+    pub fn macros(&self) -> &Macros {
+        match self {
+            Module::Template(tpl) => &tpl.macros,
+            Module::Extension(ext) => &ext.macros,
         }
     }
 }
 
 impl Block {
+    pub fn new(typ: BlockType, contents: Contents) -> Self {
+        Self {
+            typ,
+            contents,
+            parent: None,
+        }
+    }
+
     pub fn get_name(&self) -> Option<&str> {
         match &self.typ {
             BlockType::BlockName(name) => Some(name),
@@ -125,14 +305,60 @@ impl Block {
         }
     }
 
+    /// Blocks inside an `embed` override the embedded template's, not those of the template
+    /// around it.
+    pub fn is_embed(&self) -> bool {
+        matches!(self.typ, BlockType::Embed(_))
+    }
+
+    /// Makes this block override `parent`, at the end of the chain of blocks it already
+    /// overrides. Blocks nested in this one override those of the same name nested in `parent`.
     pub fn set_parents(&mut self, parent: Box<Block>) {
-        for elem in self.contents.iter_mut() {
-            match elem {
-                Content::Print(Expression::Parent) => *elem = Content::Block(parent.clone()),
-                Content::Block(block) => block.set_parents(parent.clone()),
-                _ => (),
+        if let Some(own) = &mut self.parent {
+            return Rc::make_mut(own).set_parents(parent);
+        }
+        let mut nested = HashMap::default();
+        for contents in parent.contents() {
+            collect_blocks(contents, &mut nested);
+        }
+        for contents in self.contents_mut() {
+            link_nested(contents, &mut nested);
+        }
+        self.parent = Some(Rc::new(*parent));
+    }
+
+    /// Every list of contents owned by this block, including conditional and loop `else` branches.
+    pub fn contents(&self) -> Vec<&Contents> {
+        let mut all = vec![&self.contents];
+        match &self.typ {
+            BlockType::Conditional(cond) => {
+                all.extend(cond.branches.iter().map(|b| &b.contents));
+                all.extend(cond.else_branch.as_ref());
+            }
+            BlockType::Loop(l) => all.extend(l.else_branch.as_ref()),
+            BlockType::BlockName(_)
+            | BlockType::AutoEscape(_)
+            | BlockType::Apply(_)
+            | BlockType::Embed(_) => (),
+        }
+        all
+    }
+
+    /// Mutable counterpart of [`Block::contents`].
+    pub fn contents_mut(&mut self) -> Vec<&mut Contents> {
+        let mut all = vec![&mut self.contents];
+        match &mut self.typ {
+            BlockType::Conditional(cond) => {
+                all.extend(cond.branches.iter_mut().map(|b| &mut b.contents));
+                all.extend(cond.else_branch.as_mut());
             }
+            BlockType::Loop(l) => all.extend(l.else_branch.as_mut()),
+            BlockType::BlockName(_)
+            | BlockType::AutoEscape(_)
+            | BlockType::Apply(_)
+            | BlockType::Embed(_) => (),
         }
+        all
     }
 }
 
@@ -12,7 +12,10 @@ pub enum Expression {
     Array(Vec<Expression>),
     FuncCall(FuncCall),
     FilterCall(FuncCall),
+    Test(FuncCall),
     HashMap(Vec<KeyValuePair>),
+    Ternary(Ternary),
+    MethodCall(MethodCall),
     Parent,
 }
 
@@ -22,10 +25,37 @@ pub struct Term {
     pub params: Vec<Expression>,
 }
 
+/// `cond ? then : otherwise`, where `cond ?: otherwise` leaves out `then` and `cond ? then`
+/// leaves out `otherwise`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ternary {
+    pub condition: Box<Expression>,
+    pub then: Option<Box<Expression>>,
+    pub otherwise: Option<Box<Expression>>,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct FuncCall {
     pub name: String,
     pub params: Vec<Expression>,
+    /// `name: value` arguments, which always follow the positional ones.
+    pub named: Vec<NamedArg>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct NamedArg {
+    pub name: String,
+    pub val: Expression,
+}
+
+/// `object.name(params)`
+#[derive(Debug, PartialEq, Clone)]
+pub struct MethodCall {
+    pub object: Box<Expression>,
+    pub name: String,
+    pub params: Vec<Expression>,
+    /// Only macros take named arguments.
+    pub named: Vec<NamedArg>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -2,10 +2,10 @@ use nom::{
     branch::alt,
     bytes::complete::{tag, take_while, take_while1},
     character::complete::{digit1, multispace0, multispace1, one_of},
-    combinator::{eof, map_res, not, opt, peek},
+    combinator::{eof, not, opt, peek, recognize, verify},
     error::{make_error, ErrorKind, ParseError},
-    multi::{many_till, separated_list0, separated_list1},
-    number::complete::float,
+    multi::{many0, many_till, separated_list0, separated_list1},
+    number::complete::double,
     sequence::{delimited, preceded, separated_pair, terminated, tuple},
     Err, IResult,
 };
@@ -27,7 +27,7 @@ pub enum Token {
     Parens(Vec<Token>),
     FuncCall(FuncCall),
     Op(Operator),
-    Parent(), //TODO remove
+    Colon,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -40,6 +40,8 @@ pub struct KVTokensPair {
 pub struct FuncCall {
     pub name: String,
     pub params: Vec<Vec<Token>>,
+    /// `name: value` or `name = value` arguments, which have to follow the positional ones.
+    pub named: Vec<(String, Vec<Token>)>,
 }
 
 pub fn lex_exprs(i: Span) -> IResult<Span, Vec<Token>> {
@@ -54,8 +56,9 @@ fn lex_exprs_elem(i: Span) -> IResult<Span, Token> {
 fn lex_expr(i: Span) -> IResult<Span, Token> {
     alt((
         lex_operator,
+        lex_colon,
         lex_bool,
-        lex_parent_call,
+        lex_null,
         lex_hash_map,
         lex_parens,
         lex_array,
@@ -67,16 +70,19 @@ fn lex_expr(i: Span) -> IResult<Span, Token> {
     ))(i)
 }
 
-fn lex_parent_call(i: Span) -> IResult<Span, Token> {
-    let (rest, _) = tag::<&str, Span, nom::error::Error<Span>>("parent()" /* value */)(i)?;
-    Ok((rest, Token::Parent()))
-}
-
 fn lex_bool(i: Span) -> IResult<Span, Token> {
     let (rest, word) = alt((tag("true"), tag("false")))(i)?;
     Ok((rest, Token::Bool(*word.fragment() == "true")))
 }
 
+fn lex_null(i: Span) -> IResult<Span, Token> {
+    let (rest, _) = terminated(
+        alt((tag("null"), tag("none"))),
+        not(take_while1(|c: char| c.is_alphanumeric() || c == '_')),
+    )(i)?;
+    Ok((rest, Token::Null))
+}
+
 fn lex_string_literal(i: Span) -> IResult<Span, Token> {
     let (rest, plain_str) = lex_quoted(i)?;
     Ok((rest, Token::Str(plain_str.to_string())))
@@ -106,7 +112,17 @@ fn lex_var(i: Span) -> IResult<Span, Token> {
     };
     let (rest, (part1, part2)) = tuple((
         take_while1(is_identifier),
-        take_while(|c| is_identifier(c) || ('0'..='9').contains(&c) || c == '.'),
+        recognize(many0(alt((
+            take_while1(|c| is_identifier(c) || c.is_ascii_digit()),
+            // a '..' belongs to the range operator and '.name(' to a method call
+            terminated(
+                tag("."),
+                not(alt((
+                    tag("."),
+                    recognize(tuple((take_while1(is_identifier), tag("(")))),
+                ))),
+            ),
+        )))),
     ))(i)?;
     let mut accessor = part1.to_string();
     accessor.push_str(part2.trim());
@@ -114,8 +130,8 @@ fn lex_var(i: Span) -> IResult<Span, Token> {
 }
 
 fn lex_float(i: Span) -> IResult<Span, Token> {
-    let (rest, f) = float(i)?;
-    Ok((rest, Token::Float(f.into())))
+    let (rest, f) = double(i)?;
+    Ok((rest, Token::Float(f)))
 }
 
 #[derive(Debug, PartialEq)]
@@ -136,7 +152,10 @@ impl<I> ParseError<I> for TwigError<I> {
 
 fn lex_number(i: Span) -> IResult<Span, Token> {
     //TODO add negative numbers
-    let (rest, (number, ..)) = tuple((digit1, not(one_of("e."))))(i)?;
+    let (rest, (number, ..)) = tuple((
+        digit1,
+        not(alt((tag("e"), terminated(tag("."), not(tag(".")))))),
+    ))(i)?;
 
     match str::parse(&number) {
         Ok(num) => Ok((rest, Token::Number(num))),
@@ -201,20 +220,35 @@ fn lex_func_call(i: Span) -> IResult<Span, Token> {
             || (0x7f as char <= c && c <= 0xff as char)
     };
 
-    let (rest, (_, name, param_strs)) = tuple((
+    // the tokens of an argument end at a comma or parenthesis outside of nested expressions
+    let arg = verify(
+        tuple((
+            terminated(opt(lex_arg_name), multispace0),
+            many_till(lex_exprs_elem, peek(alt((tag(","), tag(")"))))),
+        )),
+        |(_, (tokens, _))| !tokens.is_empty(),
+    );
+    let (rest, (_, name, args)) = tuple((
         multispace0,
         take_while1(is_identifier),
         delimited(
-            tag("("),
-            separated_list0(tag(","), take_while1(|c| c != ',' && c != ')')),
+            tuple((tag("("), multispace0)),
+            separated_list0(tuple((tag(","), multispace0)), arg),
             tag(")"),
         ),
     ))(i)?;
-    let mut params = Vec::with_capacity(param_strs.len());
-
-    for expr in param_strs.into_iter() {
-        let (_, tokens) = lex_exprs(expr)?;
-        params.push(tokens);
+    let mut params = Vec::with_capacity(args.len());
+    let mut named = Vec::new();
+
+    for (arg_name, (tokens, _)) in args.into_iter() {
+        match arg_name {
+            Some(arg_name) => named.push((arg_name.to_string(), tokens)),
+            // positional arguments can't follow named ones
+            None if !named.is_empty() => {
+                return Err(Err::Failure(make_error(i, ErrorKind::Verify)))
+            }
+            None => params.push(tokens),
+        }
     }
 
     Ok((
@@ -222,10 +256,25 @@ fn lex_func_call(i: Span) -> IResult<Span, Token> {
         Token::FuncCall(FuncCall {
             name: name.to_string(),
             params,
+            named,
         }),
     ))
 }
 
+/// The `name:` or `name =` in front of a named argument.
+fn lex_arg_name(i: Span) -> IResult<Span, Span> {
+    let (rest, (_, name, ..)) = tuple((
+        multispace0,
+        recognize(tuple((
+            take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+        ))),
+        multispace0,
+        alt((tag(":"), terminated(tag("="), not(tag("="))))),
+    ))(i)?;
+    Ok((rest, name))
+}
+
 fn lex_operator(i: Span) -> IResult<Span, Token> {
     let (rest, (_, op)) = tuple((
         multispace0,
@@ -234,6 +283,11 @@ fn lex_operator(i: Span) -> IResult<Span, Token> {
     Ok((rest, Token::Op(op)))
 }
 
+fn lex_colon(i: Span) -> IResult<Span, Token> {
+    let (rest, _) = tag(":")(i)?;
+    Ok((rest, Token::Colon))
+}
+
 fn lex_multi_char_operator(i: Span) -> IResult<Span, Operator> {
     let (rest, op) = alt((
         tag("//"),
@@ -253,9 +307,9 @@ fn lex_multi_char_operator(i: Span) -> IResult<Span, Operator> {
         tag(".."),
         tag("=="),
         tag("!="),
+        tag("<=>"),
         tag("<="),
         tag(">="),
-        tag("<=>"),
     ))(i)?;
     Ok((
         rest,
@@ -286,7 +340,7 @@ fn lex_multi_char_operator(i: Span) -> IResult<Span, Operator> {
 }
 
 fn lex_single_operator(i: Span) -> IResult<Span, Operator> {
-    let (rest, char) = one_of("+-*/~%|")(i)?;
+    let (rest, char) = one_of("+-*/~%|<>?.")(i)?;
     match char {
         '+' => Ok((rest, Operator::Add)),
         '-' => Ok((rest, Operator::Sub)),
@@ -295,6 +349,10 @@ fn lex_single_operator(i: Span) -> IResult<Span, Operator> {
         '~' => Ok((rest, Operator::StrConcat)),
         '%' => Ok((rest, Operator::Modulo)),
         '|' => Ok((rest, Operator::Filter)),
+        '<' => Ok((rest, Operator::Lt)),
+        '>' => Ok((rest, Operator::Gt)),
+        '?' => Ok((rest, Operator::Ternary)),
+        '.' => Ok((rest, Operator::Get)),
         _ => unreachable!(),
     }
 }
@@ -401,12 +459,88 @@ mod tests {
                 "",
                 vec![Token::FuncCall(FuncCall {
                     name: "foo".to_string(),
-                    params: vec![vec![Token::Number(1)], vec![Token::Str("two".to_string())]]
+                    params: vec![vec![Token::Number(1)], vec![Token::Str("two".to_string())]],
+                    named: vec![]
                 })]
             )
         )
     }
 
+    #[test]
+    fn test_lex_func_call_nested_arguments() {
+        let call = |name: &str, params| FuncCall {
+            name: name.to_string(),
+            params,
+            named: vec![],
+        };
+        let str = |s: &str| vec![Token::Str(s.to_string())];
+        let num = |n| vec![Token::Number(n)];
+        let hash = vec![Token::HashMap(vec![
+            KVTokensPair {
+                key: str("a"),
+                value: num(1),
+            },
+            KVTokensPair {
+                key: str("b"),
+                value: num(2),
+            },
+        ])];
+        let list = vec![Token::Array(vec![str("a.twig"), str("b.twig")])];
+        let nested = vec![Token::FuncCall(call("bar", vec![num(1)]))];
+        let named_list = FuncCall {
+            named: vec![("template".to_string(), list.clone())],
+            ..call("include", vec![])
+        };
+
+        let cases = [
+            ("number_format(2, ',', '.')", call("number_format", vec![num(2), str(","), str(".")])),
+            ("foo(')', \"(,\")", call("foo", vec![str(")"), str("(,")])),
+            ("merge({a: 1, b: 2})", call("merge", vec![hash.clone()])),
+            ("include('x.twig', {a: 1, b: 2})", call("include", vec![str("x.twig"), hash])),
+            ("include(['a.twig', 'b.twig'])", call("include", vec![list])),
+            ("include(template: ['a.twig', 'b.twig'])", named_list),
+            ("foo(bar(1), 2)", call("foo", vec![nested, num(2)])),
+            ("foo( )", call("foo", vec![])),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                unspan(lex_exprs(Span::new(input))),
+                ("", vec![Token::FuncCall(expected)]),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_lex_named_arguments() {
+        let expr = Span::new("foo(1, bar: 2, baz = a == b)");
+
+        assert_eq!(
+            unspan(lex_exprs(expr)),
+            (
+                "",
+                vec![Token::FuncCall(FuncCall {
+                    name: "foo".to_string(),
+                    params: vec![vec![Token::Number(1)]],
+                    named: vec![
+                        ("bar".to_string(), vec![Token::Number(2)]),
+                        (
+                            "baz".to_string(),
+                            vec![
+                                Token::Var("a".to_string()),
+                                Token::Op(Operator::Eq),
+                                Token::Var("b".to_string())
+                            ]
+                        )
+                    ]
+                })]
+            )
+        );
+
+        assert!(lex_exprs(Span::new("foo(bar: 1, 2)")).is_err());
+    }
+
     #[test]
     fn test_lex_expressions() {
         let expr = Span::new("2 + 3 * 4 == 14 and 'foo'  in ['foo', 'bar']");
@@ -434,6 +568,35 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_lex_range() {
+        let expr = Span::new("1..10");
+        assert_eq!(
+            unspan(lex_exprs(expr)),
+            (
+                "",
+                vec![
+                    Token::Number(1),
+                    Token::Op(Operator::Range),
+                    Token::Number(10)
+                ]
+            )
+        );
+
+        let expr = Span::new("start..end");
+        assert_eq!(
+            unspan(lex_exprs(expr)),
+            (
+                "",
+                vec![
+                    Token::Var("start".to_string()),
+                    Token::Op(Operator::Range),
+                    Token::Var("end".to_string())
+                ]
+            )
+        )
+    }
+
     #[test]
     fn test_lex_bool() {
         let t = Span::new("true");
@@ -443,6 +606,96 @@ mod tests {
         assert_eq!(unspan(lex_bool(f)), ("", Token::Bool(false)));
     }
 
+    #[test]
+    fn test_lex_comparison() {
+        let expr = Span::new("1 < 2 <=> 3 <= 4 > 5");
+        assert_eq!(
+            unspan(lex_exprs(expr)),
+            (
+                "",
+                vec![
+                    Token::Number(1),
+                    Token::Op(Operator::Lt),
+                    Token::Number(2),
+                    Token::Op(Operator::Starship),
+                    Token::Number(3),
+                    Token::Op(Operator::Lte),
+                    Token::Number(4),
+                    Token::Op(Operator::Gt),
+                    Token::Number(5)
+                ]
+            )
+        )
+    }
+
+    #[test]
+    fn test_lex_ternary() {
+        let expr = Span::new("a ? b : c ?: d");
+        assert_eq!(
+            unspan(lex_exprs(expr)),
+            (
+                "",
+                vec![
+                    Token::Var("a".to_string()),
+                    Token::Op(Operator::Ternary),
+                    Token::Var("b".to_string()),
+                    Token::Colon,
+                    Token::Var("c".to_string()),
+                    Token::Op(Operator::Ternary),
+                    Token::Colon,
+                    Token::Var("d".to_string())
+                ]
+            )
+        )
+    }
+
+    #[test]
+    fn test_lex_subscript() {
+        let expr = Span::new("foo[bar].baz");
+        assert_eq!(
+            unspan(lex_exprs(expr)),
+            (
+                "",
+                vec![
+                    Token::Var("foo".to_string()),
+                    Token::Array(vec![vec![Token::Var("bar".to_string())]]),
+                    Token::Op(Operator::Get),
+                    Token::Var("baz".to_string())
+                ]
+            )
+        )
+    }
+
+    #[test]
+    fn test_lex_method_call() {
+        let expr = Span::new("user.address.format('-')");
+        assert_eq!(
+            unspan(lex_exprs(expr)),
+            (
+                "",
+                vec![
+                    Token::Var("user.address".to_string()),
+                    Token::Op(Operator::Get),
+                    Token::FuncCall(FuncCall {
+                        name: "format".to_string(),
+                        params: vec![vec![Token::Str("-".to_string())]],
+                        named: vec![]
+                    })
+                ]
+            )
+        )
+    }
+
+    #[test]
+    fn test_lex_null() {
+        assert_eq!(unspan(lex_expr(Span::new("null"))), ("", Token::Null));
+        assert_eq!(unspan(lex_expr(Span::new("none"))), ("", Token::Null));
+        assert_eq!(
+            unspan(lex_expr(Span::new("nullable"))),
+            ("", Token::Var("nullable".to_string()))
+        );
+    }
+
     fn unspan<O>(span: IResult<Span, O>) -> (&str, O) {
         let (rest, out) = span.unwrap();
         (rest.fragment(), out)
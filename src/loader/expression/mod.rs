@@ -2,4 +2,4 @@ pub mod ast;
 mod lexer;
 mod parser;
 
-pub use parser::{parse, Operator};
+pub use parser::{parse, parse_filters, parse_func_call, parse_tag_args, Operator};
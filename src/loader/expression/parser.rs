@@ -7,8 +7,8 @@ use anyhow::{anyhow, Result};
 use crate::loader::{expression::ast::FuncCall, Span};
 
 use super::{
-    ast::{Expression, Term, KeyValuePair},
-    lexer::{lex_exprs, Token},
+    ast::{Expression, Term, KeyValuePair, MethodCall, NamedArg, Ternary},
+    lexer::{self, lex_exprs, Token},
 };
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -51,29 +51,105 @@ pub fn parse(input: Span) -> IResult<Span, Expression> {
     map_res(lex_exprs, parse_to_expression)(input)
 }
 
+/// Parses a lone call like `input(name, type = 'text')`, e.g. the signature of a macro.
+pub fn parse_func_call(input: Span) -> IResult<Span, FuncCall> {
+    map_res(lex_exprs, |tokens| -> Result<FuncCall> {
+        match <[Token; 1]>::try_from(tokens) {
+            Ok([Token::FuncCall(fc)]) => parse_call(fc),
+            _ => Err(anyhow!("expected a single call")),
+        }
+    })(input)
+}
+
+/// Parses a filter chain without a filtered value, like `upper|trim('-')` in an `apply` tag.
+pub fn parse_filters(input: Span) -> IResult<Span, Vec<FuncCall>> {
+    map_res(lex_exprs, |tokens| -> Result<Vec<FuncCall>> {
+        let mut tokens = VecDeque::from(tokens);
+        let mut filters = vec![parse_filter(&mut tokens)?];
+        while let Some(tok) = tokens.pop_front() {
+            if tok != Token::Op(Operator::Filter) {
+                return Err(anyhow!("unexpected token in filter chain: {:?}", tok));
+            }
+            filters.push(parse_filter(&mut tokens)?);
+        }
+        Ok(filters)
+    })(input)
+}
+
+/// A tag's leading expression and its keyword arguments, see [`parse_tag_args`].
+pub type TagArgs<'a> = (Expression, Vec<(&'a str, Expression)>);
+
+/// Parses the arguments of a tag like `embed`, an expression followed by any of `keywords` in
+/// any order: `'card.twig' ignore missing with {title: 'x'} only`. Returns the leading expression
+/// and each keyword found with the expression after it, null for keywords used as flags.
+pub fn parse_tag_args<'a>(
+    keywords: &'a [&'a str],
+) -> impl FnMut(Span) -> IResult<Span, TagArgs<'a>> {
+    move |input| {
+        map_res(lex_exprs, |tokens| -> Result<_> {
+            let mut segments: Vec<(Option<&str>, Vec<Token>)> = vec![(None, vec![])];
+            let mut tokens = tokens.as_slice();
+            'tokens: while let Some((tok, rest)) = tokens.split_first() {
+                for &keyword in keywords {
+                    let words = keyword.split(' ').collect::<Vec<&str>>();
+                    let is_keyword = tokens.len() >= words.len()
+                        && words
+                            .iter()
+                            .zip(tokens)
+                            .all(|(word, tok)| matches!(tok, Token::Var(var) if var == word));
+                    if is_keyword {
+                        if segments.iter().any(|(seen, _)| *seen == Some(keyword)) {
+                            return Err(anyhow!("{} is given twice", keyword));
+                        }
+                        segments.push((Some(keyword), vec![]));
+                        tokens = &tokens[words.len()..];
+                        continue 'tokens;
+                    }
+                }
+                segments.last_mut().unwrap().1.push(tok.clone());
+                tokens = rest;
+            }
+
+            let mut segments = segments.into_iter();
+            let (_, head) = segments.next().unwrap();
+            if head.is_empty() {
+                return Err(anyhow!("expected an expression before {:?}", keywords));
+            }
+            let args = segments
+                .map(|(keyword, tokens)| Ok((keyword.unwrap(), parse_to_expression(tokens)?)))
+                .collect::<Result<Vec<(&str, Expression)>>>()?;
+            Ok((parse_to_expression(head)?, args))
+        })(input)
+    }
+}
+
 pub fn parse_to_expression(tokens: Vec<Token>) -> Result<Expression> {
     let mut tokens = VecDeque::from(tokens);
-    parse_rec(&mut tokens, 0)
+    let expr = parse_rec(&mut tokens, 0)?;
+    match tokens.front() {
+        Some(tok) => Err(anyhow!("unexpected {:?} after the expression", tok)),
+        None => Ok(expr),
+    }
 }
 
 // see https://matklad.github.io/2020/04/13/simple-but-powerful-pratt-parsing.html
 fn parse_rec(tokens: &mut VecDeque<Token>, min_bp: u8) -> Result<Expression> {
-    let lhs = tokens.pop_front().unwrap_or(Token::Null);
+    let Some(lhs) = tokens.pop_front() else {
+        return Ok(Expression::Null);
+    };
 
     let mut lhs = match lhs {
-        Token::Null => {
-            return Ok(Expression::Null);
-        }
+        Token::Null => Expression::Null,
         Token::Parens(par_tokens) => parse_to_expression(par_tokens)?,
         Token::Float(f) => Expression::Float(f),
         Token::Number(n) => Expression::Number(n),
-        Token::Parent() => Expression::Parent,
         Token::Str(s) => Expression::Str(s),
         Token::Var(v) => Expression::Var(v),
         Token::Bool(b) => Expression::Bool(b),
 
         Token::Array(toks) => Expression::Array(toks
                               .into_iter()
+                              .filter(|elem| !elem.is_empty())
                               .map(parse_to_expression)
                               .collect::<Result<Vec<Expression>>>()?),
 
@@ -84,14 +160,13 @@ fn parse_rec(tokens: &mut VecDeque<Token>, min_bp: u8) -> Result<Expression> {
             })
         }).collect::<Result<Vec<KeyValuePair>>>()?),
 
-        Token::FuncCall(fc) => Expression::FuncCall(FuncCall {
-            name: fc.name,
-            params: fc
-                .params
-                .into_iter()
-                .map(parse_to_expression)
-                .collect::<Result<Vec<Expression>>>()?,
-        }),
+        Token::FuncCall(fc) if fc.name == "parent" => {
+            if !fc.params.is_empty() || !fc.named.is_empty() {
+                return Err(anyhow!("parent() takes no arguments"));
+            }
+            Expression::Parent
+        }
+        Token::FuncCall(fc) => Expression::FuncCall(parse_call(fc)?),
 
         Token::Op(op) => {
             if let Some(bp) = op.bp_prefix() {
@@ -104,15 +179,47 @@ fn parse_rec(tokens: &mut VecDeque<Token>, min_bp: u8) -> Result<Expression> {
             }
         }
 
-        _ => todo!("lhs not an atom"),
+        Token::Colon => return Err(anyhow!("unexpected ':'")),
     };
     loop {
         let op = match tokens.pop_front() {
             None => break,
             Some(Token::Op(op)) => op,
-            Some(x) => todo!("two atoms next to eachother {:?} {:?}", lhs, x),
+            // a `[` right after an operand is a subscript rather than an array literal
+            Some(Token::Array(mut keys)) => {
+                let bp = Operator::ArrayIndex.bp_postfix().expect("operator is postfix");
+                if bp < min_bp {
+                    tokens.push_front(Token::Array(keys));
+                    break;
+                }
+                let (Some(key), true) = (keys.pop(), keys.is_empty()) else {
+                    return Err(anyhow!("subscript needs exactly one key"));
+                };
+                lhs = Expression::Term(Term {
+                    op: Operator::ArrayIndex,
+                    params: vec![lhs, parse_to_expression(key)?],
+                });
+                continue;
+            }
+            // closes the `then` branch of an enclosing ternary
+            Some(Token::Colon) => {
+                tokens.push_front(Token::Colon);
+                break;
+            }
+            Some(tok) => return Err(anyhow!("unexpected {:?} after {:?}", tok, lhs)),
         };
 
+        if let Some(l_bp) = op.bp_postfix() {
+            if l_bp < min_bp {
+                tokens.push_front(Token::Op(op));
+                break;
+            }
+            let mut filter = parse_filter(tokens)?;
+            filter.params.insert(0, lhs);
+            lhs = Expression::FilterCall(filter);
+            continue;
+        }
+
         let (l_bp, r_bp) = op.bp_infix();
 
         if l_bp < min_bp {
@@ -120,31 +227,42 @@ fn parse_rec(tokens: &mut VecDeque<Token>, min_bp: u8) -> Result<Expression> {
             break;
         }
 
-        if op == Operator::Filter {
-            let Some(filter) = tokens.pop_front() else {
-                return Err(anyhow!("unexpected end of expression"));
-            };
-
-            lhs = match filter {
-                Token::Var(name) => Expression::FilterCall(FuncCall {
-                    name,
-                    params: vec![lhs],
-                }),
-                Token::FuncCall(fc) => {
-                    let super::lexer::FuncCall { name, params } = fc;
-                    let mut params: Vec<Expression> = params
-                        .into_iter()
-                        .map(parse_to_expression)
-                        .collect::<Result<Vec<Expression>>>()?;
-
-                    params.insert(0, lhs);
-
-                    Expression::FilterCall(FuncCall { name, params })
+        if op == Operator::Get {
+            match tokens.pop_front() {
+                Some(Token::Var(path)) => {
+                    for attr in path.split('.') {
+                        lhs = Expression::Term(Term {
+                            op,
+                            params: vec![lhs, Expression::Str(attr.to_string())],
+                        });
+                    }
+                }
+                Some(Token::FuncCall(fc)) => {
+                    let FuncCall {
+                        name,
+                        params,
+                        named,
+                    } = parse_call(fc)?;
+                    lhs = Expression::MethodCall(MethodCall {
+                        object: Box::new(lhs),
+                        name,
+                        params,
+                        named,
+                    });
                 }
-                _ => return Err(anyhow!("illegal filter name: {:?}", filter)),
-            };
+                _ => return Err(anyhow!("expected attribute name after '.'")),
+            }
+            continue;
+        }
 
-            break;
+        if op == Operator::Is {
+            lhs = parse_test(tokens, lhs)?;
+            continue;
+        }
+
+        if op == Operator::Ternary {
+            lhs = parse_ternary(tokens, lhs, r_bp)?;
+            continue;
         }
 
         let rhs = parse_rec(tokens, r_bp)?;
@@ -157,52 +275,177 @@ fn parse_rec(tokens: &mut VecDeque<Token>, min_bp: u8) -> Result<Expression> {
     Ok(lhs)
 }
 
+/// Parses the test following `is`, e.g. `is not divisible by(3)`. The tested value becomes the
+/// first parameter and `is not` wraps the test in a negation.
+fn parse_test(tokens: &mut VecDeque<Token>, subject: Expression) -> Result<Expression> {
+    let negated = tokens.front() == Some(&Token::Op(Operator::Not));
+    if negated {
+        tokens.pop_front();
+    }
+
+    let (mut name, mut args) = match tokens.pop_front() {
+        Some(Token::Var(name)) => (name, vec![]),
+        Some(Token::Null) => ("null".to_string(), vec![]),
+        Some(Token::FuncCall(fc)) if fc.named.is_empty() => (fc.name, fc.params),
+        Some(Token::FuncCall(fc)) => {
+            return Err(anyhow!("test {} can't take named arguments", fc.name))
+        }
+        tok => return Err(anyhow!("illegal test name: {:?}", tok)),
+    };
+
+    // two word tests like `same as(..)` or `divisible by(..)`
+    if args.is_empty() && matches!(tokens.front(), Some(Token::Var(_) | Token::FuncCall(_))) {
+        match tokens.pop_front() {
+            Some(Token::Var(word)) => name = format!("{} {}", name, word),
+            Some(Token::FuncCall(fc)) if fc.named.is_empty() => {
+                name = format!("{} {}", name, fc.name);
+                args = fc.params;
+            }
+            Some(Token::FuncCall(fc)) => {
+                return Err(anyhow!("test {} {} can't take named arguments", name, fc.name))
+            }
+            tok => return Err(anyhow!("illegal test name: {:?}", tok)),
+        }
+    }
+
+    let mut params = vec![subject];
+    for arg in args {
+        params.push(parse_to_expression(arg)?);
+    }
+    let test = Expression::Test(FuncCall {
+        name,
+        params,
+        named: vec![],
+    });
+
+    Ok(if negated {
+        Expression::Term(Term {
+            op: Operator::Not,
+            params: vec![test],
+        })
+    } else {
+        test
+    })
+}
+
+/// Parses the filter following `|`, with the arguments it takes besides the filtered value.
+fn parse_filter(tokens: &mut VecDeque<Token>) -> Result<FuncCall> {
+    match tokens.pop_front() {
+        Some(Token::Var(name)) => Ok(FuncCall {
+            name,
+            params: vec![],
+            named: vec![],
+        }),
+        Some(Token::FuncCall(fc)) => parse_call(fc),
+        None => Err(anyhow!("unexpected end of expression")),
+        Some(tok) => Err(anyhow!("illegal filter name: {:?}", tok)),
+    }
+}
+
+fn parse_call(fc: lexer::FuncCall) -> Result<FuncCall> {
+    Ok(FuncCall {
+        name: fc.name,
+        params: fc
+            .params
+            .into_iter()
+            .map(parse_to_expression)
+            .collect::<Result<Vec<Expression>>>()?,
+        named: fc
+            .named
+            .into_iter()
+            .map(|(name, val)| -> Result<NamedArg> {
+                Ok(NamedArg {
+                    name,
+                    val: parse_to_expression(val)?,
+                })
+            })
+            .collect::<Result<Vec<NamedArg>>>()?,
+    })
+}
+
+fn parse_ternary(
+    tokens: &mut VecDeque<Token>,
+    condition: Expression,
+    r_bp: u8,
+) -> Result<Expression> {
+    let then = match tokens.front() {
+        Some(Token::Colon) => None,
+        _ => Some(Box::new(parse_rec(tokens, 0)?)),
+    };
+    let otherwise = match tokens.pop_front() {
+        Some(Token::Colon) => Some(Box::new(parse_rec(tokens, r_bp)?)),
+        None => None,
+        Some(tok) => return Err(anyhow!("unexpected token in ternary: {:?}", tok)),
+    };
+    if then.is_none() && otherwise.is_none() {
+        return Err(anyhow!("ternary without branches"));
+    }
+
+    Ok(Expression::Ternary(Ternary {
+        condition: Box::new(condition),
+        then,
+        otherwise,
+    }))
+}
+
 trait BindingPower {
     fn bp_infix(&self) -> (u8, u8);
     fn bp_prefix(&self) -> Option<u8>;
+    fn bp_postfix(&self) -> Option<u8>;
 }
 
+// Relative precedence follows Twig's operator table. Left associative operators
+// bind tighter on their right side, right associative ones on their left side.
 impl BindingPower for Operator {
     fn bp_infix(&self) -> (u8, u8) {
-        match self {
-            Self::Get => (63, 62),
-            &Self::ArrayIndex => unreachable!("operator is postfix"),
-            Self::Filter => (61, 60),
-            Self::NullCoal => (59, 58),
-            Self::Exp => (57, 56),
-            Self::Is => (55, 54),
-            Self::Modulo => (53, 52),
-            Self::Divi => (51, 50),
-            Self::Div => (49, 48),
-            Self::Mul => (47, 46),
-            Self::StrConcat => (45, 44),
-            Self::Sub => (43, 42),
-            Self::Add => (41, 40),
-            Self::Range => (39, 38),
-            Self::EndsWith => (37, 36),
-            Self::StartsWith => (35, 34),
-            Self::Matches => (33, 32),
-            Self::In => (31, 30),
-            Self::Lte => (27, 26),
-            Self::Gte => (25, 24),
-            Self::Gt => (23, 22),
-            Self::Lt => (21, 20),
-            Self::Starship => (19, 18),
+        let (rank, right_assoc) = match self {
+            Self::Get => (18, false),
+            Self::Filter | Self::ArrayIndex => unreachable!("operator is postfix"),
+            Self::NullCoal => (14, true),
+            Self::Exp => (13, true),
+            Self::Is => (12, false),
+            Self::Mul | Self::Div | Self::Divi | Self::Modulo => (11, false),
+            Self::StrConcat => (10, false),
+            Self::Add | Self::Sub => (9, false),
+            Self::Range => (8, false),
+            Self::Eq
+            | Self::Neq
+            | Self::Starship
+            | Self::Lt
+            | Self::Gt
+            | Self::Lte
+            | Self::Gte
+            | Self::In
+            | Self::Matches
+            | Self::StartsWith
+            | Self::EndsWith => (7, false),
+            Self::BAnd => (6, false),
+            Self::BXor => (5, false),
+            Self::BOr => (4, false),
             Self::Not => unreachable!("operator is prefix"),
-            Self::Neq => (15, 14),
-            Self::Eq => (13, 12),
-            Self::And => (11, 10),
-            Self::Or => (9, 8),
-            Self::BOr => (7, 6),
-            Self::BXor => (5, 4),
-            Self::BAnd => (3, 2),
-            Self::Ternary => todo!("ternary not yet supported"),
+            Self::And => (2, false),
+            Self::Or => (1, false),
+            Self::Ternary => (0, true),
+        };
+        if right_assoc {
+            (rank * 2 + 1, rank * 2)
+        } else {
+            (rank * 2, rank * 2 + 1)
         }
     }
 
     fn bp_prefix(&self) -> Option<u8> {
         match self {
-            Self::Not => Some(16),
+            Self::Not => Some(6),
+            Self::Sub | Self::Add => Some(34),
+            _ => None,
+        }
+    }
+
+    // like in Twig, filters bind tighter than unary operators: `-1|abs` is `-(1|abs)`
+    fn bp_postfix(&self) -> Option<u8> {
+        match self {
+            Self::ArrayIndex | Self::Filter => Some(36),
             _ => None,
         }
     }
@@ -210,7 +453,7 @@ impl BindingPower for Operator {
 
 #[cfg(test)]
 mod tests {
-    use crate::loader::expression::{ast::FuncCall, lexer};
+    use crate::loader::expression::ast::FuncCall;
 
     use super::*;
     use pretty_assertions::assert_eq;
@@ -276,6 +519,7 @@ mod tests {
                 Token::Op(Operator::Add),
                 Token::Number(2),
             ]],
+            named: vec![],
         })];
 
         assert_eq!(
@@ -285,7 +529,8 @@ mod tests {
                 params: vec![Expression::Term(Term {
                     op: Operator::Add,
                     params: vec![Expression::Number(1), Expression::Number(2)]
-                })]
+                })],
+                named: vec![]
             })
         )
     }
@@ -323,4 +568,343 @@ mod tests {
             })
         )
     }
+
+    #[test]
+    fn test_associativity() {
+        let (_, sub) = parse(Span::new("5 - 2 - 1")).unwrap();
+        assert_eq!(
+            sub,
+            Expression::Term(Term {
+                op: Operator::Sub,
+                params: vec![
+                    Expression::Term(Term {
+                        op: Operator::Sub,
+                        params: vec![Expression::Number(5), Expression::Number(2)]
+                    }),
+                    Expression::Number(1)
+                ]
+            })
+        );
+
+        let (_, exp) = parse(Span::new("2 ** 3 ** 2")).unwrap();
+        assert_eq!(
+            exp,
+            Expression::Term(Term {
+                op: Operator::Exp,
+                params: vec![
+                    Expression::Number(2),
+                    Expression::Term(Term {
+                        op: Operator::Exp,
+                        params: vec![Expression::Number(3), Expression::Number(2)]
+                    })
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_comparison_precedence() {
+        let (_, expr) = parse(Span::new("1 + 1 > 1 b-and 2 ~ 3 == '23'")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::Term(Term {
+                op: Operator::BAnd,
+                params: vec![
+                    Expression::Term(Term {
+                        op: Operator::Gt,
+                        params: vec![
+                            Expression::Term(Term {
+                                op: Operator::Add,
+                                params: vec![Expression::Number(1), Expression::Number(1)]
+                            }),
+                            Expression::Number(1)
+                        ]
+                    }),
+                    Expression::Term(Term {
+                        op: Operator::Eq,
+                        params: vec![
+                            Expression::Term(Term {
+                                op: Operator::StrConcat,
+                                params: vec![Expression::Number(2), Expression::Number(3)]
+                            }),
+                            Expression::Str("23".to_string())
+                        ]
+                    })
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_ternary() {
+        let (_, expr) = parse(Span::new("a or b ? 1 : c ? 2 : 3")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::Ternary(Ternary {
+                condition: Box::new(Expression::Term(Term {
+                    op: Operator::Or,
+                    params: vec![
+                        Expression::Var("a".to_string()),
+                        Expression::Var("b".to_string())
+                    ]
+                })),
+                then: Some(Box::new(Expression::Number(1))),
+                otherwise: Some(Box::new(Expression::Ternary(Ternary {
+                    condition: Box::new(Expression::Var("c".to_string())),
+                    then: Some(Box::new(Expression::Number(2))),
+                    otherwise: Some(Box::new(Expression::Number(3))),
+                }))),
+            })
+        );
+
+        let (_, elvis) = parse(Span::new("a ?: 'default'")).unwrap();
+        assert_eq!(
+            elvis,
+            Expression::Ternary(Ternary {
+                condition: Box::new(Expression::Var("a".to_string())),
+                then: None,
+                otherwise: Some(Box::new(Expression::Str("default".to_string()))),
+            })
+        );
+
+        let (_, short) = parse(Span::new("a ? b ? 1 : 2")).unwrap();
+        assert_eq!(
+            short,
+            Expression::Ternary(Ternary {
+                condition: Box::new(Expression::Var("a".to_string())),
+                then: Some(Box::new(Expression::Ternary(Ternary {
+                    condition: Box::new(Expression::Var("b".to_string())),
+                    then: Some(Box::new(Expression::Number(1))),
+                    otherwise: Some(Box::new(Expression::Number(2))),
+                }))),
+                otherwise: None,
+            })
+        );
+
+        for input in [":", "a : b", "a ? b : c : d", "a b"] {
+            assert!(parse(Span::new(input)).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_is() {
+        let (_, expr) = parse(Span::new("a is not divisible by(3) and b is null")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::Term(Term {
+                op: Operator::And,
+                params: vec![
+                    Expression::Term(Term {
+                        op: Operator::Not,
+                        params: vec![Expression::Test(FuncCall {
+                            name: "divisible by".to_string(),
+                            params: vec![Expression::Var("a".to_string()), Expression::Number(3)],
+                            named: vec![]
+                        })]
+                    }),
+                    Expression::Test(FuncCall {
+                        name: "null".to_string(),
+                        params: vec![Expression::Var("b".to_string())],
+                        named: vec![]
+                    })
+                ]
+            })
+        );
+
+        let (_, expr) = parse(Span::new("a.b is defined")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::Test(FuncCall {
+                name: "defined".to_string(),
+                params: vec![Expression::Var("a.b".to_string())],
+                named: vec![]
+            })
+        );
+
+        for input in ["x is divisible by(num: 3)", "a is same as(value: b)", "a is odd(n: 1)"] {
+            assert!(parse(Span::new(input)).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_subscript() {
+        let (_, expr) = parse(Span::new("-foo[bar ~ 'x'][0].baz.qux")).unwrap();
+        let subscript = |params| {
+            Expression::Term(Term {
+                op: Operator::ArrayIndex,
+                params,
+            })
+        };
+        let get = |params| {
+            Expression::Term(Term {
+                op: Operator::Get,
+                params,
+            })
+        };
+        assert_eq!(
+            expr,
+            Expression::Term(Term {
+                op: Operator::Sub,
+                params: vec![get(vec![
+                    get(vec![
+                        subscript(vec![
+                            subscript(vec![
+                                Expression::Var("foo".to_string()),
+                                Expression::Term(Term {
+                                    op: Operator::StrConcat,
+                                    params: vec![
+                                        Expression::Var("bar".to_string()),
+                                        Expression::Str("x".to_string())
+                                    ]
+                                })
+                            ]),
+                            Expression::Number(0)
+                        ]),
+                        Expression::Str("baz".to_string())
+                    ]),
+                    Expression::Str("qux".to_string())
+                ])]
+            })
+        );
+    }
+
+    #[test]
+    fn test_method_call() {
+        let (_, expr) = parse(Span::new("user.getName().upper(1, 2)")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::MethodCall(MethodCall {
+                object: Box::new(Expression::MethodCall(MethodCall {
+                    object: Box::new(Expression::Var("user".to_string())),
+                    name: "getName".to_string(),
+                    params: vec![],
+                    named: vec![]
+                })),
+                name: "upper".to_string(),
+                params: vec![Expression::Number(1), Expression::Number(2)],
+                named: vec![]
+            })
+        );
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let filter = |name: &str, params| {
+            Expression::FilterCall(FuncCall {
+                name: name.to_string(),
+                params,
+                named: vec![],
+            })
+        };
+
+        let (_, expr) = parse(Span::new("x|lower|trim")).unwrap();
+        assert_eq!(
+            expr,
+            filter(
+                "trim",
+                vec![filter("lower", vec![Expression::Var("x".to_string())])]
+            )
+        );
+
+        let (_, expr) = parse(Span::new("x|default('a') ~ 'b'")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::Term(Term {
+                op: Operator::StrConcat,
+                params: vec![
+                    filter(
+                        "default",
+                        vec![Expression::Var("x".to_string()), Expression::Str("a".to_string())]
+                    ),
+                    Expression::Str("b".to_string())
+                ]
+            })
+        );
+
+        let (_, expr) = parse(Span::new("-1|abs + 2|abs")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::Term(Term {
+                op: Operator::Add,
+                params: vec![
+                    Expression::Term(Term {
+                        op: Operator::Sub,
+                        params: vec![filter("abs", vec![Expression::Number(1)])]
+                    }),
+                    filter("abs", vec![Expression::Number(2)])
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_parent() {
+        let (_, expr) = parse(Span::new("parent()|upper")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::FilterCall(FuncCall {
+                name: "upper".to_string(),
+                params: vec![Expression::Parent],
+                named: vec![],
+            })
+        );
+
+        assert!(parse(Span::new("parent('title')")).is_err());
+    }
+
+    #[test]
+    fn test_named_arguments() {
+        let (_, expr) = parse(Span::new("x|round(1, method: 'floor')")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::FilterCall(FuncCall {
+                name: "round".to_string(),
+                params: vec![Expression::Var("x".to_string()), Expression::Number(1)],
+                named: vec![NamedArg {
+                    name: "method".to_string(),
+                    val: Expression::Str("floor".to_string())
+                }]
+            })
+        );
+
+        assert!(parse(Span::new("x|round(precision: 1, 'floor')")).is_err());
+
+        let (_, expr) = parse(Span::new("date(timezone = 'UTC')")).unwrap();
+        assert_eq!(
+            expr,
+            Expression::FuncCall(FuncCall {
+                name: "date".to_string(),
+                params: vec![],
+                named: vec![NamedArg {
+                    name: "timezone".to_string(),
+                    val: Expression::Str("UTC".to_string())
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_filters() {
+        let (_, filters) = parse_filters(Span::new("upper|trim('-', side: 'left')")).unwrap();
+        assert_eq!(
+            filters,
+            vec![
+                FuncCall {
+                    name: "upper".to_string(),
+                    params: vec![],
+                    named: vec![]
+                },
+                FuncCall {
+                    name: "trim".to_string(),
+                    params: vec![Expression::Str("-".to_string())],
+                    named: vec![NamedArg {
+                        name: "side".to_string(),
+                        val: Expression::Str("left".to_string())
+                    }]
+                }
+            ]
+        );
+
+        assert!(parse_filters(Span::new("upper ~ 'x'")).is_err());
+    }
 }
@@ -1,16 +1,16 @@
 pub mod ast;
 pub mod expression;
 pub mod parser;
-use std::{collections::HashMap, fs::File, io::Read, path::PathBuf};
+use std::{collections::HashMap, fs::File, io::Read, path::PathBuf, rc::Rc};
 
-use self::ast::Content;
+use self::ast::{Block, BlockType, Macro, Use};
 pub use self::{
-    ast::{Extension, Module, Template},
+    ast::{Extension, Module},
     expression::Operator,
     parser::{parse, Span},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 pub struct Loader {
     root_dir: PathBuf,
@@ -26,22 +26,21 @@ impl Loader {
     }
 
     pub fn load<T: AsRef<str>>(&mut self, template: T) -> Result<Module> {
-        match self.modules.get(template.as_ref()) {
-            Some(t) => Ok(t.to_owned()),
-            None => match self.read_file(template.as_ref())? {
-                Module::Template(mut tpl) => {
-                    tpl = self.load_includes(tpl)?;
-                    self.modules
-                        .insert(template.as_ref().into(), Module::Template(tpl));
-                    Ok(self.modules[template.as_ref()].clone())
-                }
-                Module::Extension(ext) => {
-                    self.modules
-                        .insert(template.as_ref().into(), Module::Extension(ext));
-                    Ok(self.modules[template.as_ref()].clone())
-                }
-            },
+        self.load_cached(template.as_ref()).cloned()
+    }
+
+    /// The macro `name` of `template`, without copying the rest of the template.
+    pub fn load_macro(&mut self, template: &str, name: &str) -> Result<Option<Rc<Macro>>> {
+        Ok(self.load_cached(template)?.macros().get(name).cloned())
+    }
+
+    fn load_cached(&mut self, template: &str) -> Result<&Module> {
+        if !self.modules.contains_key(template) {
+            let module = self.read_file(template)?;
+            let module = self.apply_uses(module)?;
+            self.modules.insert(template.into(), module);
         }
+        Ok(&self.modules[template])
     }
 
     fn read_file(&mut self, name: &str) -> Result<Module> {
@@ -54,19 +53,40 @@ impl Loader {
         parse(name.to_string(), &buf)
     }
 
-    fn load_includes(&mut self, template: Template) -> Result<Template> {
-        let mut replace_fn = Box::new(|content: Content| -> Content {
-            match content {
-                Content::Statement(ast::Stmt::Include(name)) => {
-                    match self.read_file(&name).expect("todo!!") {
-                        Module::Template(tpl) => tpl.into_block(),
-                        _ => todo!(),
-                    }
-                }
-                _ => content,
+    fn apply_uses(&mut self, module: Module) -> Result<Module> {
+        match module {
+            Module::Template(mut tpl) => {
+                let used = self.used_blocks(&tpl.uses)?;
+                tpl.use_blocks(used);
+                Ok(Module::Template(tpl))
             }
-        });
+            Module::Extension(mut ext) => {
+                let used = self.used_blocks(&ext.uses)?;
+                ext.use_blocks(used);
+                Ok(Module::Extension(ext))
+            }
+        }
+    }
 
-        Ok(template.replace_includes(&mut replace_fn))
+    /// The blocks imported by `uses`, where later imports override earlier ones.
+    fn used_blocks(&mut self, uses: &[Use]) -> Result<HashMap<String, Box<Block>>> {
+        let mut blocks = HashMap::default();
+        for used in uses {
+            let mut traits = match self.load(&used.template)? {
+                Module::Template(tpl) => tpl.traits(),
+                Module::Extension(_) => {
+                    return Err(anyhow!("{} extends a template and can't be used", used.template))
+                }
+            };
+            for (name, alias) in used.renames.iter() {
+                let mut block = traits
+                    .remove(name)
+                    .ok_or_else(|| anyhow!("block {} is not defined in {}", name, used.template))?;
+                block.typ = BlockType::BlockName(alias.clone());
+                traits.insert(alias.clone(), block);
+            }
+            blocks.extend(traits);
+        }
+        Ok(blocks)
     }
 }
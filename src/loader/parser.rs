@@ -1,68 +1,152 @@
 use super::{
     ast::{
-        get_blocks, Block, BlockType, Content, Extension, IterationType, Loop, Module, Setter,
-        Stmt, Template,
+        get_blocks, Block, BlockType, Branch, Conditional, Content, Contents, Extension, Import,
+        ImportTarget, Include, IterationType, Loop, Macro, MacroParam, Macros, Module, Setter,
+        Stmt, Template, Use,
     },
-    expression,
+    expression::{self, ast::Expression},
 };
 
-use std::collections::HashMap;
+use std::{collections::HashMap, rc::Rc};
 
 use anyhow::{anyhow, Result};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_till, take_until, take_while, take_while1},
     character::complete::{line_ending, multispace0, multispace1, space0},
-    combinator::{eof, opt},
-    multi::many_till,
-    sequence::{delimited, tuple},
-    IResult,
+    combinator::{eof, map, opt},
+    error::{make_error, ErrorKind},
+    multi::{many_till, separated_list1},
+    sequence::{delimited, preceded, terminated, tuple},
+    IResult, InputTake,
 };
 
 use nom_locate::LocatedSpan;
 pub type Span<'a> = LocatedSpan<&'a str>;
 
 pub fn parse(name: String, input: &str) -> Result<Module> {
-    let input = Span::new(input);
-    if let Ok((rest, parent)) = parse_extends(input) {
-        match parse_contents(rest) {
-            Ok((_, content)) => {
-                let ext = Extension {
-                    name,
-                    parent,
-                    blocks: get_blocks(content, HashMap::default()),
-                };
-                Ok(Module::Extension(ext))
+    let ModuleParts {
+        mut content,
+        mut macros,
+        uses,
+        parent,
+    } = match parse_module(Span::new(input)) {
+        Ok((_, parts)) => parts,
+        Err(err) => return Err(anyhow!("error parsing {}: {}", name, err)),
+    };
+    hoist_imports(&name, &mut content, &mut macros);
+
+    match parent {
+        Some(parent) => Ok(Module::Extension(Extension {
+            name,
+            parent,
+            blocks: get_blocks(content, HashMap::default()),
+            macros,
+            uses,
+        })),
+        None => Ok(Module::Template(Template {
+            name,
+            content,
+            macros,
+            uses,
+            blocks: HashMap::default(),
+        })),
+    }
+}
+
+/// Makes the template's own imports, `_self` included, available from the start of the
+/// template, of each top-level block and of each macro. Blocks end up in a parent template and
+/// macros are called from other templates, but still resolve macros like where they were
+/// written.
+fn hoist_imports(name: &str, content: &mut Contents, macros: &mut Macros) {
+    let import_self = Content::Statement(Stmt::Import(Import {
+        template: Expression::Str(name.to_string()),
+        target: ImportTarget::Alias("_self".to_string()),
+    }));
+    let mut imports = vec![import_self.clone()];
+    imports.extend(
+        content
+            .iter()
+            .filter(|c| matches!(c, Content::Statement(Stmt::Import(_))))
+            .cloned(),
+    );
+
+    for elem in content.iter_mut() {
+        match elem {
+            Content::Block(block) if block.get_name().is_some() => {
+                block.contents.splice(0..0, imports.iter().cloned());
             }
-            Err(err) => Err(anyhow!("error parsing {}: {}", name, err)),
-        }
-    } else {
-        match parse_contents(input) {
-            Ok((_, content)) => Ok(Module::Template(Template { name, content })),
-            Err(err) => Err(anyhow!("error parsing {}: {}", name, err)),
+            _ => (),
         }
     }
+    for mac in macros.values_mut() {
+        Rc::make_mut(mac).contents.splice(0..0, imports.iter().cloned());
+    }
+    content.insert(0, import_self);
 }
 
-fn parse_extends(i: Span) -> IResult<Span, String> {
-    let (rest, (.., parent)) = delimited(
+/// `{% extends 'parent.twig' %}`, the parent being any expression evaluated when rendering.
+fn parse_extends(i: Span) -> IResult<Span, Expression> {
+    let (rest, parent) = delimited(
         parse_block_tag_l,
-        tuple((tag("extends"), multispace1, parse_quoted)),
+        preceded(tuple((tag("extends"), multispace1)), parse_tag_body("%}")),
         parse_block_tag_r,
     )(i)?;
-    Ok((rest, parent.to_string()))
+    let (_, parent) = expression::parse(parent)?;
+    Ok((rest, parent))
 }
 
+/// `{%`, or `{%-` and `{%~` trimming the whitespace before them, newlines excepted for `~`.
 fn parse_block_tag_l(i: Span) -> IResult<Span, ()> {
-    let (rest, _) = tuple((space0, tag("{%"), multispace1))(i)?;
+    let (rest, _) = alt((
+        delimited(multispace0, tag("{%-"), multispace0),
+        delimited(space0, tag("{%~"), multispace0),
+        delimited(space0, tag("{%"), multispace1),
+    ))(i)?;
     Ok((rest, ()))
 }
 
+/// `%}`, eating the newline after it, or `-%}` and `~%}` trimming the whitespace after them,
+/// newlines excepted for `~`.
 fn parse_block_tag_r(i: Span) -> IResult<Span, ()> {
-    let (rest, _) = tuple((multispace0, tag("%}"), opt(line_ending)))(i)?;
+    let (rest, _) = preceded(
+        multispace0,
+        alt((
+            terminated(tag("-%}"), multispace0),
+            terminated(tag("~%}"), space0),
+            terminated(tag("%}"), opt(line_ending)),
+        )),
+    )(i)?;
     Ok((rest, ()))
 }
 
+/// The inside of a tag, up to `end` or to the whitespace control modifier right before it.
+fn parse_tag_body<'a>(end: &'static str) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, Span<'a>> {
+    move |i| {
+        let (_, body) = take_until(end)(i)?;
+        let body = body.fragment();
+        let len = body.strip_suffix(['-', '~']).unwrap_or(body).len();
+        Ok(i.take_split(len))
+    }
+}
+
+/// The whitespace control modifier of the tag or comment `i` starts with, if any.
+fn tag_modifier(i: &str) -> Option<char> {
+    let after_tag = ["{{", "{%", "{#"]
+        .iter()
+        .find_map(|open| i.strip_prefix(open))?;
+    after_tag.chars().next().filter(|c| matches!(c, '-' | '~'))
+}
+
+/// The length of `text` left once the tag in `next` trimmed the whitespace before it.
+fn untrimmed_len(text: &str, next: &str) -> usize {
+    match tag_modifier(next) {
+        Some('-') => text.trim_end().len(),
+        Some(_) => text.trim_end_matches([' ', '\t']).len(),
+        None => text.len(),
+    }
+}
+
 fn parse_quoted(i: Span) -> IResult<Span, Span> {
     alt((
         delimited(
@@ -78,40 +162,170 @@ fn parse_quoted(i: Span) -> IResult<Span, Span> {
     ))(i)
 }
 
-fn parse_contents(i: Span) -> IResult<Span, Vec<Content>> {
-    let (_, (contents, _)) = many_till(parse_content, eof)(i)?;
-    Ok((Span::new(""), contents))
+/// The top level of a template, with the macros it defines, the templates it uses and the one
+/// it extends apart from its contents.
+struct ModuleParts {
+    content: Contents,
+    macros: Macros,
+    uses: Vec<Use>,
+    parent: Option<Expression>,
+}
+
+fn parse_module(i: Span) -> IResult<Span, ModuleParts> {
+    enum TopLevel {
+        Content(Content),
+        Macro(Macro),
+        Use(Use),
+        Extends(Expression),
+    }
+
+    let (_, (elems, _)) = many_till(
+        alt((
+            map(parse_extends, TopLevel::Extends),
+            map(parse_macro, TopLevel::Macro),
+            map(parse_use, TopLevel::Use),
+            map(parse_content, TopLevel::Content),
+        )),
+        eof,
+    )(i)?;
+
+    let mut parts = ModuleParts {
+        content: Contents::default(),
+        macros: Macros::default(),
+        uses: Vec::new(),
+        parent: None,
+    };
+    for elem in elems {
+        match elem {
+            TopLevel::Content(content) => parts.content.push(content),
+            TopLevel::Macro(mac) => {
+                parts.macros.insert(mac.name.clone(), Rc::new(mac));
+            }
+            TopLevel::Use(used) => parts.uses.push(used),
+            TopLevel::Extends(_) if parts.parent.is_some() => {
+                return Err(nom::Err::Failure(make_error(i, ErrorKind::Verify)))
+            }
+            TopLevel::Extends(parent) => parts.parent = Some(parent),
+        }
+    }
+    Ok((Span::new(""), parts))
 }
 
 fn parse_content(i: Span) -> IResult<Span, Content> {
-    alt((parse_print, parse_statement, parse_block, parse_text))(i)
+    alt((
+        parse_print,
+        parse_statement,
+        parse_block,
+        parse_conditional,
+        parse_apply,
+        parse_embed,
+        parse_verbatim,
+        parse_text,
+    ))(i)
 }
 
+/// Text up to the next tag, skipping comments. A `{` that doesn't open a tag or a comment is
+/// part of the text.
 fn parse_text(i: Span) -> IResult<Span, Content> {
-    let (rest, text) = take_while1(|c| c != '{')(i)?;
-    Ok((rest, Content::Text(text.to_string())))
+    let mut text = String::new();
+    let mut rest = i;
+    loop {
+        let (after, chunk) = take_while(|c| c != '{')(rest)?;
+        let len = untrimmed_len(chunk.fragment(), after.fragment());
+        text.push_str(&chunk.fragment()[..len]);
+        if after.fragment().starts_with("{#") {
+            (rest, _) = parse_comment(after)?;
+        } else if after.fragment().starts_with("{{") || after.fragment().starts_with("{%") {
+            // Whitespace trimmed by the tag is left for it to consume.
+            (rest, _) = rest.take_split(len);
+            break;
+        } else if after.fragment().is_empty() {
+            rest = after;
+            break;
+        } else {
+            let (after, brace) = after.take_split(1);
+            text.push_str(brace.fragment());
+            rest = after;
+        }
+    }
+    if rest.location_offset() == i.location_offset() {
+        return Err(nom::Err::Error(make_error(i, ErrorKind::TakeWhile1)));
+    }
+    Ok((rest, Content::Text(text)))
+}
+
+/// `{# ... #}`, eating the newline after it, with `-#}` and `~#}` trimming the whitespace after
+/// it like tags do.
+fn parse_comment(i: Span) -> IResult<Span, ()> {
+    let (rest, _) = tuple((
+        tag("{#"),
+        parse_tag_body("#}"),
+        alt((
+            terminated(tag("-#}"), multispace0),
+            terminated(tag("~#}"), space0),
+            terminated(tag("#}"), opt(line_ending)),
+        )),
+    ))(i)?;
+    Ok((rest, ()))
+}
+
+/// `{% verbatim %}...{% endverbatim %}`, output as is.
+fn parse_verbatim(i: Span) -> IResult<Span, Content> {
+    let (mut rest, _) = delimited(parse_block_tag_l, tag("verbatim"), parse_block_tag_r)(i)?;
+    let mut text = String::new();
+    loop {
+        let (after, chunk) = take_until("{%")(rest)?;
+        let len = untrimmed_len(chunk.fragment(), after.fragment());
+        let (end_tag, _) = rest.take_split(len);
+        if let Ok((rest, _)) = parse_end_tag("endverbatim")(end_tag) {
+            text.push_str(&chunk.fragment()[..len]);
+            return Ok((rest, Content::Text(text)));
+        }
+        let (after, open) = after.take_split(2);
+        text.push_str(chunk.fragment());
+        text.push_str(open.fragment());
+        rest = after;
+    }
 }
 
 fn parse_print(i: Span) -> IResult<Span, Content> {
-    let (rest, expr) = delimited(parse_print_tag_l, take_until("}}"), parse_print_tag_r)(i)?;
+    let (rest, expr) = delimited(parse_print_tag_l, parse_tag_body("}}"), parse_print_tag_r)(i)?;
     let (_, expr) = expression::parse(expr)?;
     Ok((rest, Content::Print(expr)))
 }
 
+/// `{{`, or `{{-` and `{{~` trimming the whitespace before them, newlines excepted for `~`.
 fn parse_print_tag_l(i: Span) -> IResult<Span, ()> {
-    let (rest, _) = tuple((tag("{{"), multispace1))(i)?;
+    let (rest, _) = alt((
+        delimited(multispace0, tag("{{-"), multispace0),
+        delimited(space0, tag("{{~"), multispace0),
+        terminated(tag("{{"), multispace1),
+    ))(i)?;
     Ok((rest, ()))
 }
 
+/// `}}`, or `-}}` and `~}}` trimming the whitespace after them, newlines excepted for `~`.
 fn parse_print_tag_r(i: Span) -> IResult<Span, ()> {
-    let (rest, _) = tuple((multispace0, tag("}}")))(i)?;
+    let (rest, _) = preceded(
+        multispace0,
+        alt((
+            terminated(tag("-}}"), multispace0),
+            terminated(tag("~}}"), space0),
+            tag("}}"),
+        )),
+    )(i)?;
     Ok((rest, ()))
 }
 
 fn parse_statement(i: Span) -> IResult<Span, Content> {
     let (rest, statement) = delimited(
         parse_block_tag_l,
-        alt((parse_set_statement, parse_include_statement)),
+        alt((
+            parse_set_statement,
+            parse_include_statement,
+            parse_import_statement,
+            parse_from_statement,
+        )),
         parse_block_tag_r,
     )(i)?;
     Ok((rest, Content::Statement(statement)))
@@ -124,7 +338,7 @@ fn parse_set_statement(i: Span) -> IResult<Span, Stmt> {
         take_till(|c| c == '='),
         nom::character::complete::char('='),
         multispace0,
-        take_until("%}"),
+        parse_tag_body("%}"),
     ))(i)?;
     let (_, expr) = expression::parse(expr)?;
     Ok((
@@ -137,82 +351,384 @@ fn parse_set_statement(i: Span) -> IResult<Span, Stmt> {
 }
 
 fn parse_include_statement(i: Span) -> IResult<Span, Stmt> {
-    let (rest, (.., target)) = tuple((tag("include"), multispace1, parse_quoted))(i)?;
-    Ok((rest, Stmt::Include(target.to_string())))
+    let (rest, args) = preceded(tuple((tag("include"), multispace1)), parse_tag_body("%}"))(i)?;
+    let (_, include) = parse_include_args(args)?;
+    Ok((rest, Stmt::Include(include)))
+}
+
+fn parse_import_statement(i: Span) -> IResult<Span, Stmt> {
+    let (rest, (.., template, _, alias)) = tuple((
+        tag("import"),
+        multispace1,
+        take_until(" as "),
+        tuple((multispace1, tag("as"), multispace1)),
+        parse_identifier,
+    ))(i)?;
+    let (_, template) = expression::parse(template)?;
+    Ok((
+        rest,
+        Stmt::Import(Import {
+            template,
+            target: ImportTarget::Alias(alias.to_string()),
+        }),
+    ))
+}
+
+fn parse_from_statement(i: Span) -> IResult<Span, Stmt> {
+    let (rest, (.., template, _, names)) = tuple((
+        tag("from"),
+        multispace1,
+        take_until(" import "),
+        tuple((multispace1, tag("import"), multispace1)),
+        separated_list1(
+            tuple((multispace0, tag(","), multispace0)),
+            tuple((
+                parse_identifier,
+                opt(preceded(
+                    tuple((multispace1, tag("as"), multispace1)),
+                    parse_identifier,
+                )),
+            )),
+        ),
+    ))(i)?;
+    let (_, template) = expression::parse(template)?;
+    let names = names
+        .into_iter()
+        .map(|(name, alias)| (name.to_string(), alias.unwrap_or(name).to_string()))
+        .collect();
+    Ok((
+        rest,
+        Stmt::Import(Import {
+            template,
+            target: ImportTarget::Macros(names),
+        }),
+    ))
+}
+
+/// `{% use 'blocks.twig' %}`, optionally renaming blocks: `with sidebar as base_sidebar, ...`.
+fn parse_use(i: Span) -> IResult<Span, Use> {
+    let rename = tuple((
+        parse_identifier,
+        tuple((multispace1, tag("as"), multispace1)),
+        parse_identifier,
+    ));
+    let (rest, (.., template, renames)) = delimited(
+        parse_block_tag_l,
+        tuple((
+            tag("use"),
+            multispace1,
+            parse_quoted,
+            opt(preceded(
+                tuple((multispace1, tag("with"), multispace1)),
+                separated_list1(tuple((multispace0, tag(","), multispace0)), rename),
+            )),
+        )),
+        parse_block_tag_r,
+    )(i)?;
+    let renames = renames
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, _, alias)| (name.to_string(), alias.to_string()))
+        .collect();
+    Ok((
+        rest,
+        Use {
+            template: template.to_string(),
+            renames,
+        },
+    ))
+}
+
+/// `{% macro input(name, type = 'text') %}...{% endmacro %}`, the end tag may repeat the name.
+fn parse_macro(i: Span) -> IResult<Span, Macro> {
+    let (rest, signature) = delimited(
+        parse_block_tag_l,
+        preceded(tuple((tag("macro"), multispace1)), parse_tag_body("%}")),
+        parse_block_tag_r,
+    )(i)?;
+    let (_, signature) = expression::parse_func_call(signature)?;
+
+    let mut params = Vec::with_capacity(signature.params.len() + signature.named.len());
+    for param in signature.params {
+        match param {
+            Expression::Var(name) => params.push(MacroParam {
+                name,
+                default: None,
+            }),
+            _ => return Err(nom::Err::Failure(make_error(i, ErrorKind::Verify))),
+        }
+    }
+    params.extend(signature.named.into_iter().map(|arg| MacroParam {
+        name: arg.name,
+        default: Some(arg.val),
+    }));
+
+    let end_tag = delimited(
+        parse_block_tag_l,
+        tuple((
+            tag("endmacro"),
+            opt(preceded(multispace1, parse_identifier)),
+        )),
+        parse_block_tag_r,
+    );
+    let (rest, (contents, _)) = many_till(parse_content, end_tag)(rest)?;
+    Ok((
+        rest,
+        Macro {
+            name: signature.name,
+            params,
+            contents,
+        },
+    ))
 }
 
 fn parse_block(i: Span) -> IResult<Span, Content> {
     let (rest, typ) = parse_block_type(i)?;
     match typ {
         BlockType::BlockName(_) => {
-            let (rest, (contents, _)) = many_till(
-                parse_content,
-                tuple((tag("{% endblock %}"), opt(line_ending))),
-            )(rest)?;
-            Ok((rest, Content::Block(Box::new(Block { typ, contents }))))
+            let (rest, (contents, _)) =
+                many_till(parse_content, parse_end_tag("endblock"))(rest)?;
+            Ok((rest, Content::Block(Box::new(Block::new(typ, contents)))))
         }
-        BlockType::Loop(_) => {
-            let (rest, (contents, _)) = many_till(
+        BlockType::Loop(mut l) => {
+            let (rest, (contents, has_else)) = many_till(
                 parse_content,
-                tuple((tag("{% endfor %}"), opt(line_ending))),
+                alt((
+                    map(parse_end_tag("else"), |_| true),
+                    map(parse_end_tag("endfor"), |_| false),
+                )),
             )(rest)?;
-            Ok((rest, Content::Block(Box::new(Block { typ, contents }))))
+            let (rest, else_branch) = if has_else {
+                let (rest, (contents, _)) =
+                    many_till(parse_content, parse_end_tag("endfor"))(rest)?;
+                (rest, Some(contents))
+            } else {
+                (rest, None)
+            };
+            l.else_branch = else_branch;
+            let typ = BlockType::Loop(l);
+            Ok((rest, Content::Block(Box::new(Block::new(typ, contents)))))
+        }
+        BlockType::AutoEscape(_) => {
+            let (rest, (contents, _)) =
+                many_till(parse_content, parse_end_tag("endautoescape"))(rest)?;
+            Ok((rest, Content::Block(Box::new(Block::new(typ, contents)))))
         }
+        BlockType::Conditional(_) => unreachable!("conditionals are parsed by parse_conditional"),
+        BlockType::Apply(_) => unreachable!("apply blocks are parsed by parse_apply"),
+        BlockType::Embed(_) => unreachable!("embeds are parsed by parse_embed"),
+    }
+}
+
+/// `{% apply upper|trim %}...{% endapply %}`, or the same with `filter`/`endfilter`.
+fn parse_apply(i: Span) -> IResult<Span, Content> {
+    let (rest, (tag_name, filters)) = delimited(
+        parse_block_tag_l,
+        tuple((
+            alt((tag("apply"), tag("filter"))),
+            preceded(multispace1, parse_tag_body("%}")),
+        )),
+        parse_block_tag_r,
+    )(i)?;
+    let (_, filters) = expression::parse_filters(filters)?;
+    let end_tag = match *tag_name.fragment() {
+        "apply" => "endapply",
+        _ => "endfilter",
+    };
+    let (rest, (contents, _)) = many_till(parse_content, parse_end_tag(end_tag))(rest)?;
+
+    let typ = BlockType::Apply(filters);
+    Ok((rest, Content::Block(Box::new(Block::new(typ, contents)))))
+}
+
+/// `{% embed 'card.twig' with {title: 'x'} %}{% block body %}...{% endblock %}{% endembed %}`
+fn parse_embed(i: Span) -> IResult<Span, Content> {
+    let (rest, args) = delimited(
+        parse_block_tag_l,
+        preceded(tuple((tag("embed"), multispace1)), parse_tag_body("%}")),
+        parse_block_tag_r,
+    )(i)?;
+    let (_, include) = parse_include_args(args)?;
+    let (rest, (contents, _)) = many_till(parse_content, parse_end_tag("endembed"))(rest)?;
+
+    let typ = BlockType::Embed(include);
+    Ok((rest, Content::Block(Box::new(Block::new(typ, contents)))))
+}
+
+/// The template of an `include` or `embed` tag followed by its options, `ignore missing`,
+/// `with` and `only`.
+fn parse_include_args(i: Span) -> IResult<Span, Include> {
+    let (rest, (template, args)) =
+        expression::parse_tag_args(&["ignore missing", "with", "only"])(i)?;
+    let mut include = Include {
+        template,
+        vars: None,
+        only: false,
+        ignore_missing: false,
+    };
+    for (keyword, expr) in args {
+        match keyword {
+            "with" => include.vars = Some(expr),
+            _ if expr != Expression::Null => {
+                return Err(nom::Err::Failure(make_error(i, ErrorKind::Verify)))
+            }
+            "only" => include.only = true,
+            _ => include.ignore_missing = true,
+        }
+    }
+    Ok((rest, include))
+}
+
+enum BranchEnd {
+    ElseIf(Expression),
+    Else,
+    EndIf,
+}
+
+fn parse_conditional(i: Span) -> IResult<Span, Content> {
+    let (mut rest, mut condition) = parse_condition_tag("if")(i)?;
+    let mut branches = Vec::default();
+
+    let else_branch = loop {
+        let (after, (contents, end)) = many_till(parse_content, parse_branch_end)(rest)?;
+        branches.push(Branch {
+            condition,
+            contents,
+        });
+        rest = after;
+
+        match end {
+            BranchEnd::ElseIf(next) => condition = next,
+            BranchEnd::Else => {
+                let (after, (contents, _)) =
+                    many_till(parse_content, parse_end_tag("endif"))(rest)?;
+                rest = after;
+                break Some(contents);
+            }
+            BranchEnd::EndIf => break None,
+        }
+    };
+
+    let typ = BlockType::Conditional(Conditional {
+        branches,
+        else_branch,
+    });
+    let block = Block::new(typ, Contents::default());
+    Ok((rest, Content::Block(Box::new(block))))
+}
+
+fn parse_branch_end(i: Span) -> IResult<Span, BranchEnd> {
+    alt((
+        |i| parse_condition_tag("elseif")(i).map(|(rest, cond)| (rest, BranchEnd::ElseIf(cond))),
+        |i| parse_end_tag("else")(i).map(|(rest, _)| (rest, BranchEnd::Else)),
+        |i| parse_end_tag("endif")(i).map(|(rest, _)| (rest, BranchEnd::EndIf)),
+    ))(i)
+}
+
+fn parse_condition_tag<'a>(
+    name: &'static str,
+) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, Expression> {
+    move |i| {
+        let (rest, expr) = delimited(
+            parse_block_tag_l,
+            preceded(tuple((tag(name), multispace1)), parse_tag_body("%}")),
+            parse_block_tag_r,
+        )(i)?;
+        let (_, expr) = expression::parse(expr)?;
+        Ok((rest, expr))
+    }
+}
+
+fn parse_end_tag<'a>(name: &'static str) -> impl FnMut(Span<'a>) -> IResult<Span<'a>, ()> {
+    move |i| {
+        delimited(parse_block_tag_l, tag(name), parse_block_tag_r)(i).map(|(rest, _)| (rest, ()))
     }
 }
 
 fn parse_block_type(i: Span) -> IResult<Span, BlockType> {
     delimited(
         parse_block_tag_l,
-        alt((parse_block_name, parse_loop)),
+        alt((parse_block_name, parse_loop, parse_autoescape)),
         parse_block_tag_r,
     )(i)
 }
 
+fn parse_autoescape(i: Span) -> IResult<Span, BlockType> {
+    let (rest, (_, strategy)) = tuple((
+        tag("autoescape"),
+        opt(preceded(
+            multispace1,
+            alt((
+                map(parse_quoted, |s| Some(s.to_string())),
+                map(tag("true"), |_| Some("html".to_string())),
+                map(tag("false"), |_| None),
+            )),
+        )),
+    ))(i)?;
+    Ok((
+        rest,
+        BlockType::AutoEscape(strategy.unwrap_or_else(|| Some("html".to_string()))),
+    ))
+}
+
 fn parse_block_name(i: Span) -> IResult<Span, BlockType> {
-    let (rest, (.., name)) = tuple((tag("block"), multispace1, (take_till(|c| c == ' '))))(i)?;
+    let (rest, (.., name)) = tuple((tag("block"), multispace1, parse_identifier))(i)?;
     Ok((rest, BlockType::BlockName(name.to_string())))
 }
 
 fn parse_loop(i: Span) -> IResult<Span, BlockType> {
-    let (rest, (.., iter_type, _, _, iterator)) = tuple((
+    let (rest, (.., iter_type, _, _, _, iterator)) = tuple((
         tag("for"),
         multispace1,
         alt((parse_key_value, parse_single_var)),
+        multispace1,
         tag("in"),
         multispace1,
-        take_till(|c| c == ' '),
+        parse_tag_body("%}"),
     ))(i)?;
+    let (_, (iterator, args)) = expression::parse_tag_args(&["if"])(iterator)?;
+    let condition = args.into_iter().next().map(|(_, condition)| condition);
     Ok((
         rest,
         BlockType::Loop(Loop {
             typ: iter_type,
-            iterator: iterator.to_string(),
+            iterator,
+            condition,
+            else_branch: None,
         }),
     ))
 }
 
 fn parse_single_var(i: Span) -> IResult<Span, IterationType> {
-    let (rest, varname) = take_until("in")(i)?;
-    Ok((rest, IterationType::SingleVal(varname.trim().to_string())))
+    let (rest, varname) = parse_identifier(i)?;
+    Ok((rest, IterationType::SingleVal(varname.to_string())))
 }
 
 fn parse_key_value(i: Span) -> IResult<Span, IterationType> {
     let (rest, (keyname, .., valname)) = tuple((
-        take_till(|c| c == ',' || c == '%'),
+        parse_identifier,
+        multispace0,
         nom::character::complete::char(','),
         multispace0,
-        take_until("in"),
+        parse_identifier,
     ))(i)?;
     Ok((
         rest,
-        IterationType::KeyVal((keyname.trim().to_string(), valname.trim().to_string())),
+        IterationType::KeyVal((keyname.to_string(), valname.to_string())),
     ))
 }
 
+fn parse_identifier(i: Span) -> IResult<Span, Span> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_')(i)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::loader::{
+        expression::ast::{FuncCall, KeyValuePair, Term},
+        Operator,
+    };
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -228,8 +744,29 @@ mod tests {
         let extends = Span::new("{% extends 'parent.html.twig' %}");
         assert_eq!(
             unspan(parse_extends(extends)),
-            ("", "parent.html.twig".to_string())
-        )
+            ("", Expression::Str("parent.html.twig".to_string()))
+        );
+
+        let extends = Span::new("{% extends ['a.twig', layout] %}");
+        assert_eq!(
+            unspan(parse_extends(extends)),
+            (
+                "",
+                Expression::Array(vec![
+                    Expression::Str("a.twig".to_string()),
+                    Expression::Var("layout".to_string()),
+                ])
+            )
+        );
+
+        let input = "{% use 'a.twig' %}\n{% extends layout %}";
+        let Ok(Module::Extension(ext)) = parse("child.twig".to_string(), input) else {
+            panic!("expected an extension");
+        };
+        assert_eq!(ext.parent, Expression::Var("layout".to_string()));
+
+        let input = "{% extends 'a.twig' %}{% extends 'b.twig' %}";
+        assert!(parse("child.twig".to_string(), input).is_err());
     }
 
     #[test]
@@ -237,10 +774,411 @@ mod tests {
         let input = Span::new(r#"first{# comment #}"#);
         assert_eq!(
             unspan(parse_text(input)),
-            ("{# comment #}", Content::Text("first".to_string()))
+            ("", Content::Text("first".to_string()))
+        );
+
+        let input = Span::new("a { color: red; }{# {{ x }} #} b \n {#- c -#} \n c{{ d }}");
+        assert_eq!(
+            unspan(parse_text(input)),
+            ("{{ d }}", Content::Text("a { color: red; } bc".to_string()))
+        );
+
+        let input = Span::new("a {{- x }}");
+        assert_eq!(
+            unspan(parse_text(input)),
+            (" {{- x }}", Content::Text("a".to_string()))
+        );
+        assert!(parse_text(Span::new("{% if x %}")).is_err());
+    }
+
+    #[test]
+    fn test_parse_verbatim() {
+        let input = Span::new("{% verbatim %}{{ x }}{% if %} \n{%- endverbatim %}rest");
+        assert_eq!(
+            unspan(parse_verbatim(input)),
+            ("rest", Content::Text("{{ x }}{% if %}".to_string()))
+        );
+        assert!(parse_verbatim(Span::new("{% verbatim %}{{ x }}")).is_err());
+    }
+
+    #[test]
+    fn test_parse_whitespace_control() {
+        let text = |s: &str| Content::Text(s.to_string());
+        let print = |s: &str| Content::Print(Expression::Var(s.to_string()));
+        let cases = [
+            ("a \n {{- x -}} \n b", vec![text("a"), print("x"), text("b")]),
+            ("a \n {{~ x ~}} \n b", vec![text("a \n"), print("x"), text("\n b")]),
+            ("a \n {{-x}} b", vec![text("a"), print("x"), text(" b")]),
+            ("a {{ x }} \n b", vec![text("a "), print("x"), text(" \n b")]),
+            (" \n {{- x }}", vec![print("x")]),
+        ];
+        for (input, contents) in cases {
+            let (_, (parsed, _)) = many_till(parse_content, eof)(Span::new(input)).unwrap();
+            assert_eq!(parsed, contents, "{}", input);
+        }
+
+        let input = Span::new("<ul> \n {%- for i in l ~%} \n <li>{%~ endfor -%} \n </ul>");
+        let (_, (parsed, _)) = many_till(parse_content, eof)(input).unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                text("<ul>"),
+                Content::Block(Box::new(Block {
+                    typ: BlockType::Loop(Loop {
+                        typ: IterationType::SingleVal("i".to_string()),
+                        iterator: Expression::Var("l".to_string()),
+                        condition: None,
+                        else_branch: None,
+                    }),
+                    contents: vec![text("\n <li>")],
+                    parent: None,
+                })),
+                text("</ul>"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_conditional() {
+        let input = Span::new("{% if a %}A{% elseif b %}B{% else %}C{% endif %}rest");
+        assert_eq!(
+            unspan(parse_conditional(input)),
+            (
+                "rest",
+                Content::Block(Box::new(Block {
+                    typ: BlockType::Conditional(Conditional {
+                        branches: vec![
+                            Branch {
+                                condition: Expression::Var("a".to_string()),
+                                contents: vec![Content::Text("A".to_string())],
+                            },
+                            Branch {
+                                condition: Expression::Var("b".to_string()),
+                                contents: vec![Content::Text("B".to_string())],
+                            },
+                        ],
+                        else_branch: Some(vec![Content::Text("C".to_string())]),
+                    }),
+                    contents: vec![],
+                    parent: None,
+                }))
+            )
+        )
+    }
+
+    #[test]
+    fn test_parse_loop_iterable() {
+        let input = Span::new("{% for index in 1..10 %}");
+        assert_eq!(
+            unspan(parse_block_type(input)),
+            (
+                "",
+                BlockType::Loop(Loop {
+                    typ: IterationType::SingleVal("index".to_string()),
+                    iterator: Expression::Term(Term {
+                        op: Operator::Range,
+                        params: vec![Expression::Number(1), Expression::Number(10)],
+                    }),
+                    condition: None,
+                    else_branch: None,
+                })
+            )
         )
     }
 
+    #[test]
+    fn test_parse_loop_condition() {
+        let input = Span::new("{% for k, v in items if v %}");
+        assert_eq!(
+            unspan(parse_block_type(input)),
+            (
+                "",
+                BlockType::Loop(Loop {
+                    typ: IterationType::KeyVal(("k".to_string(), "v".to_string())),
+                    iterator: Expression::Var("items".to_string()),
+                    condition: Some(Expression::Var("v".to_string())),
+                    else_branch: None,
+                })
+            )
+        );
+
+        let input = Span::new("{% for v in ['a if b', v]\n\tif v != ' if ' %}");
+        let (_, BlockType::Loop(l)) = unspan(parse_block_type(input)) else {
+            panic!("not a loop");
+        };
+        let Expression::Array(values) = l.iterator else {
+            panic!("iterator is not an array: {:?}", l.iterator);
+        };
+        assert_eq!(values[0], Expression::Str("a if b".to_string()));
+        let Some(Expression::Term(condition)) = l.condition else {
+            panic!("condition is not a comparison: {:?}", l.condition);
+        };
+        assert_eq!(condition.params[1], Expression::Str(" if ".to_string()));
+    }
+
+    #[test]
+    fn test_parse_loop_else() {
+        let input = Span::new("{% for x in xs %}A{% else %}B{% endfor %}rest");
+        assert_eq!(
+            unspan(parse_block(input)),
+            (
+                "rest",
+                Content::Block(Box::new(Block {
+                    typ: BlockType::Loop(Loop {
+                        typ: IterationType::SingleVal("x".to_string()),
+                        iterator: Expression::Var("xs".to_string()),
+                        condition: None,
+                        else_branch: Some(vec![Content::Text("B".to_string())]),
+                    }),
+                    contents: vec![Content::Text("A".to_string())],
+                    parent: None,
+                }))
+            )
+        )
+    }
+
+    #[test]
+    fn test_parse_autoescape() {
+        let cases = [
+            ("{% autoescape %}", Some("html")),
+            ("{% autoescape 'js' %}", Some("js")),
+            ("{% autoescape \"css\" %}", Some("css")),
+            ("{% autoescape true %}", Some("html")),
+            ("{% autoescape false %}", None),
+        ];
+        for (input, strategy) in cases {
+            assert_eq!(
+                unspan(parse_block_type(Span::new(input))),
+                ("", BlockType::AutoEscape(strategy.map(str::to_string)))
+            )
+        }
+
+        let input = Span::new("{% autoescape false %}{{ x }}{% endautoescape %}rest");
+        assert_eq!(
+            unspan(parse_block(input)),
+            (
+                "rest",
+                Content::Block(Box::new(Block {
+                    typ: BlockType::AutoEscape(None),
+                    contents: vec![Content::Print(Expression::Var("x".to_string()))],
+                    parent: None,
+                }))
+            )
+        )
+    }
+
+    #[test]
+    fn test_parse_apply() {
+        let upper = FuncCall {
+            name: "upper".to_string(),
+            params: vec![],
+            named: vec![],
+        };
+        let input = Span::new("{% apply upper %}{{ x }}{% endapply %}rest");
+        assert_eq!(
+            unspan(parse_apply(input)),
+            (
+                "rest",
+                Content::Block(Box::new(Block {
+                    typ: BlockType::Apply(vec![upper.clone()]),
+                    contents: vec![Content::Print(Expression::Var("x".to_string()))],
+                    parent: None,
+                }))
+            )
+        );
+
+        let input = Span::new("{% filter upper|raw %}x{% endfilter %}");
+        let raw = FuncCall {
+            name: "raw".to_string(),
+            ..upper.clone()
+        };
+        assert_eq!(
+            unspan(parse_apply(input)),
+            (
+                "",
+                Content::Block(Box::new(Block {
+                    typ: BlockType::Apply(vec![upper, raw]),
+                    contents: vec![Content::Text("x".to_string())],
+                    parent: None,
+                }))
+            )
+        );
+
+        assert!(parse_apply(Span::new("{% apply upper %}x{% endfilter %}")).is_err());
+    }
+
+    #[test]
+    fn test_parse_include() {
+        let input = Span::new("{% include ['a.twig', name] with vars only %}rest");
+        assert_eq!(
+            unspan(parse_statement(input)),
+            (
+                "rest",
+                Content::Statement(Stmt::Include(Include {
+                    template: Expression::Array(vec![
+                        Expression::Str("a.twig".to_string()),
+                        Expression::Var("name".to_string()),
+                    ]),
+                    vars: Some(Expression::Var("vars".to_string())),
+                    only: true,
+                    ignore_missing: false,
+                }))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_embed() {
+        let input = Span::new(
+            "{% embed 'card.twig' ignore missing with {a: 1} only %}\
+             {% block body %}x{% endblock %}{% endembed %}rest",
+        );
+        let body = Block {
+            typ: BlockType::BlockName("body".to_string()),
+            contents: vec![Content::Text("x".to_string())],
+            parent: None,
+        };
+        assert_eq!(
+            unspan(parse_embed(input)),
+            (
+                "rest",
+                Content::Block(Box::new(Block {
+                    typ: BlockType::Embed(Include {
+                        template: Expression::Str("card.twig".to_string()),
+                        vars: Some(Expression::HashMap(vec![KeyValuePair {
+                            key: Expression::Str("a".to_string()),
+                            val: Expression::Number(1),
+                        }])),
+                        only: true,
+                        ignore_missing: true,
+                    }),
+                    contents: vec![Content::Block(Box::new(body))],
+                    parent: None,
+                }))
+            )
+        );
+
+        let input = Span::new("{% embed 'card.twig' %}{% endembed %}");
+        assert_eq!(
+            unspan(parse_embed(input)),
+            (
+                "",
+                Content::Block(Box::new(Block {
+                    typ: BlockType::Embed(Include {
+                        template: Expression::Str("card.twig".to_string()),
+                        vars: None,
+                        only: false,
+                        ignore_missing: false,
+                    }),
+                    contents: vec![],
+                    parent: None,
+                }))
+            )
+        );
+
+        assert!(parse_embed(Span::new("{% embed 'card.twig' only x %}{% endembed %}")).is_err());
+    }
+
+    #[test]
+    fn test_parse_import() {
+        let input = Span::new("{% import 'forms.twig' as forms %}rest");
+        assert_eq!(
+            unspan(parse_statement(input)),
+            (
+                "rest",
+                Content::Statement(Stmt::Import(Import {
+                    template: Expression::Str("forms.twig".to_string()),
+                    target: ImportTarget::Alias("forms".to_string()),
+                }))
+            )
+        );
+
+        let input = Span::new("{% from _self import input, label as field_label %}");
+        assert_eq!(
+            unspan(parse_statement(input)),
+            (
+                "",
+                Content::Statement(Stmt::Import(Import {
+                    template: Expression::Var("_self".to_string()),
+                    target: ImportTarget::Macros(vec![
+                        ("input".to_string(), "input".to_string()),
+                        ("label".to_string(), "field_label".to_string()),
+                    ]),
+                }))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_use() {
+        let input = Span::new("{% use 'blocks.twig' %}rest");
+        assert_eq!(
+            unspan(parse_use(input)),
+            (
+                "rest",
+                Use {
+                    template: "blocks.twig".to_string(),
+                    renames: vec![],
+                }
+            )
+        );
+
+        let input = Span::new("{% use 'blocks.twig' with sidebar as base_sidebar, nav as menu %}");
+        assert_eq!(
+            unspan(parse_use(input)),
+            (
+                "",
+                Use {
+                    template: "blocks.twig".to_string(),
+                    renames: vec![
+                        ("sidebar".to_string(), "base_sidebar".to_string()),
+                        ("nav".to_string(), "menu".to_string()),
+                    ],
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_macro() {
+        let input = Span::new("{% macro input(name, type = 'text') %}{{ name }}{% endmacro %}rest");
+        assert_eq!(
+            unspan(parse_macro(input)),
+            (
+                "rest",
+                Macro {
+                    name: "input".to_string(),
+                    params: vec![
+                        MacroParam {
+                            name: "name".to_string(),
+                            default: None,
+                        },
+                        MacroParam {
+                            name: "type".to_string(),
+                            default: Some(Expression::Str("text".to_string())),
+                        },
+                    ],
+                    contents: vec![Content::Print(Expression::Var("name".to_string()))],
+                }
+            )
+        );
+
+        let input = Span::new("{% macro hr() %}<hr>{% endmacro hr %}");
+        assert_eq!(
+            unspan(parse_macro(input)),
+            (
+                "",
+                Macro {
+                    name: "hr".to_string(),
+                    params: vec![],
+                    contents: vec![Content::Text("<hr>".to_string())],
+                }
+            )
+        );
+
+        assert!(parse_macro(Span::new("{% macro input('name') %}{% endmacro %}")).is_err());
+    }
+
     fn unspan<O>(span: IResult<Span, O>) -> (&str, O) {
         let (rest, out) = span.unwrap();
         (rest.fragment(), out)